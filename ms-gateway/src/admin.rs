@@ -0,0 +1,228 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+
+use crate::body::{self, ResponseBody};
+use crate::connection::ConnectionStats;
+use crate::limits;
+use crate::pool::EndpointStatus;
+use crate::AppState;
+
+#[derive(Serialize)]
+struct Status<'a> {
+    maintenance: bool,
+    ready: bool,
+    connections: &'a ConnectionStats,
+    endpoints: Vec<EndpointStatus>,
+}
+
+#[derive(Deserialize)]
+struct AddEndpoint {
+    uri: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+struct SetWeight {
+    weight: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Maintenance {
+    enabled: bool,
+}
+
+/// Serves the admin API on its own listener until the process exits.
+///
+/// | Method | Path                      | Action                          |
+/// |--------|---------------------------|---------------------------------|
+/// | GET    | /status                   | maintenance, connections, pool  |
+/// | GET    | /endpoints                | worker endpoints with stats     |
+/// | POST   | /endpoints                | add `{"uri": .., "weight": ..}` |
+/// | DELETE | /endpoints/{id}           | remove an endpoint              |
+/// | POST   | /endpoints/{id}/drain     | stop sending new requests       |
+/// | PUT    | /endpoints/{id}/weight    | set `{"weight": ..}`            |
+/// | GET    | /connections              | connection and request counts   |
+/// | GET    | /maintenance              | maintenance mode                |
+/// | PUT    | /maintenance              | set `{"enabled": ..}`           |
+/// | POST   | /shutdown                 | start a graceful shutdown       |
+/// | POST   | /upgrade                  | hand over to a new process      |
+/// | GET    | /ready                    | 503 once draining or maintained |
+/// | GET    | /live                     | 200 while the process runs      |
+pub async fn serve(addr: SocketAddr, state: Arc<AppState>) {
+    let listener = loop {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => break listener,
+            // Held by the process being replaced during an upgrade
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+            Err(e) => {
+                eprintln!("admin listener error: {}: {}", addr, e);
+                return;
+            }
+        }
+    };
+    println!("Admin listening on {}", addr);
+
+    let mut accept_backoff = None;
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                accept_backoff = limits::accept_backoff(&e, accept_backoff);
+                match accept_backoff {
+                    Some(delay) => {
+                        eprintln!("admin accept error: {}, pausing for {:?}", e, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => eprintln!("admin accept error: {}", e),
+                }
+                continue;
+            }
+        };
+        accept_backoff = None;
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle(req, state.clone()));
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                eprintln!("admin connection error: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    state: Arc<AppState>,
+) -> Result<Response<ResponseBody>, hyper::Error> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let body = req.into_body().collect().await?.to_bytes();
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let pool = &state.pool;
+
+    let res = match (&method, segments.as_slice()) {
+        (&Method::GET, ["status"]) => json(
+            StatusCode::OK,
+            &Status {
+                maintenance: state.maintenance.load(Ordering::Relaxed),
+                ready: state.is_ready(),
+                connections: &state.connections,
+                endpoints: pool.statuses(),
+            },
+        ),
+        (&Method::GET, ["endpoints"]) => json(StatusCode::OK, &pool.statuses()),
+        (&Method::POST, ["endpoints"]) => match serde_json::from_slice::<AddEndpoint>(&body) {
+            Ok(add) => match pool.add(&add.uri, add.weight) {
+                Ok(status) => json(StatusCode::CREATED, &status),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e),
+            },
+            Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+        },
+        (&Method::DELETE, ["endpoints", id]) => {
+            found(id.parse().ok().and_then(|id| pool.remove(id)))
+        }
+        (&Method::POST, ["endpoints", id, "drain"]) => {
+            found(id.parse().ok().and_then(|id| pool.drain(id)))
+        }
+        (&Method::PUT, ["endpoints", id, "weight"]) => {
+            match serde_json::from_slice::<SetWeight>(&body) {
+                Ok(set) => found(
+                    id.parse()
+                        .ok()
+                        .and_then(|id| pool.set_weight(id, set.weight)),
+                ),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::GET, ["connections"]) => json(StatusCode::OK, &*state.connections),
+        (&Method::GET, ["maintenance"]) => json(
+            StatusCode::OK,
+            &Maintenance {
+                enabled: state.maintenance.load(Ordering::Relaxed),
+            },
+        ),
+        (&Method::PUT, ["maintenance"]) => match serde_json::from_slice::<Maintenance>(&body) {
+            Ok(maintenance) => {
+                state
+                    .maintenance
+                    .store(maintenance.enabled, Ordering::Relaxed);
+                println!(
+                    "maintenance mode {}",
+                    if maintenance.enabled { "on" } else { "off" }
+                );
+                json(StatusCode::OK, &maintenance)
+            }
+            Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+        },
+        (&Method::POST, ["shutdown"]) => {
+            println!("shutdown requested through the admin API");
+            state.shutdown.notify_one();
+            json(
+                StatusCode::ACCEPTED,
+                &serde_json::json!({ "shutdown": true }),
+            )
+        }
+        (&Method::POST, ["upgrade"]) => {
+            state.upgrade.notify_one();
+            json(
+                StatusCode::ACCEPTED,
+                &serde_json::json!({ "upgrade": true }),
+            )
+        }
+        (&Method::GET, ["ready"]) => {
+            let ready = state.is_ready();
+            let status = if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            json(status, &serde_json::json!({ "ready": ready }))
+        }
+        (&Method::GET, ["live"]) => json(StatusCode::OK, &serde_json::json!({ "live": true })),
+        _ => error(StatusCode::NOT_FOUND, "no such admin resource"),
+    };
+    Ok(res)
+}
+
+fn found(status: Option<EndpointStatus>) -> Response<ResponseBody> {
+    match status {
+        Some(status) => json(StatusCode::OK, &status),
+        None => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response<ResponseBody> {
+    json(status, &serde_json::json!({ "error": message }))
+}
+
+fn json<T: Serialize + ?Sized>(status: StatusCode, value: &T) -> Response<ResponseBody> {
+    let bytes = serde_json::to_vec(value).unwrap_or_default();
+    let mut res = Response::new(body::full(bytes));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    res
+}
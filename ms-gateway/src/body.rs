@@ -0,0 +1,28 @@
+use std::convert::Infallible;
+
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::HeaderMap;
+use tower::BoxError;
+
+/// Body type of every response produced by the executor. Not `Sync`, so
+/// streamed bodies can read from gRPC streams. A streamed body failing
+/// aborts the response rather than ending it early.
+pub type ResponseBody = UnsyncBoxBody<Bytes, BoxError>;
+
+pub fn full<B: Into<Bytes>>(bytes: B) -> ResponseBody {
+    Full::new(bytes.into())
+        .map_err(|never: Infallible| match never {})
+        .boxed_unsync()
+}
+
+/// Body made of a single data frame followed by trailers.
+///
+/// The length is left unknown on purpose: HTTP/1 can only carry trailers in
+/// a chunked body, and hyper picks chunked encoding when no length is known.
+/// hyper also drops HTTP/1 trailers unless the client sent `TE: trailers`.
+pub fn with_trailers<B: Into<Bytes>>(bytes: B, trailers: HeaderMap) -> ResponseBody {
+    let frames = vec![Ok(Frame::data(bytes.into())), Ok(Frame::trailers(trailers))];
+    StreamBody::new(futures::stream::iter(frames)).boxed_unsync()
+}
@@ -0,0 +1,1088 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, StatusCode};
+use lru::LruCache;
+use prost::Message;
+
+// httpgrpc - protos
+use protos::httpgrpc::{HttpRequest, HttpResponse};
+
+use crate::config::CacheConfig;
+use crate::convert::{to_grpc_headers, to_http_headers};
+
+// Status codes cacheable by default (RFC 9110 section 15.1)
+const CACHEABLE_STATUS: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// How a response was obtained, reported to clients in `X-Cache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Revalidated,
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Revalidated => "REVALIDATED",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+/// Shared cache of worker responses, following RFC 9111 for a shared cache.
+///
+/// Entries live in memory up to `max_memory` bytes; the least recently used
+/// ones are moved to `disk_path` when configured, or dropped otherwise.
+#[derive(Debug)]
+pub struct ResponseCache {
+    config: CacheConfig,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    // Request headers each URI varies on, and the variant keys stored for it
+    variants: HashMap<String, Variants>,
+    memory: LruCache<String, Entry>,
+    memory_used: usize,
+    disk: LruCache<String, DiskEntry>,
+    disk_used: usize,
+    next_file: u64,
+}
+
+#[derive(Debug, Default)]
+struct Variants {
+    vary: Vec<HeaderName>,
+    keys: HashSet<String>,
+}
+
+#[derive(Debug, Clone)]
+struct EntryMeta {
+    stored_at: Instant,
+    initial_age: Duration,
+    freshness: Duration,
+    size: usize,
+}
+
+impl EntryMeta {
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    meta: EntryMeta,
+    response: HttpResponse,
+}
+
+#[derive(Debug)]
+struct DiskEntry {
+    meta: EntryMeta,
+    path: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> std::io::Result<ResponseCache> {
+        // Entries do not survive a restart, so leftovers are removed
+        if let Some(disk_path) = &config.disk_path {
+            std::fs::create_dir_all(disk_path)?;
+            for file in std::fs::read_dir(disk_path)? {
+                let path = file?.path();
+                if path.extension().is_some_and(|ext| ext == "entry") {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+
+        Ok(ResponseCache {
+            config,
+            inner: Mutex::new(Inner {
+                variants: HashMap::new(),
+                memory: LruCache::unbounded(),
+                memory_used: 0,
+                disk: LruCache::unbounded(),
+                disk_used: 0,
+                next_file: 0,
+            }),
+        })
+    }
+
+    /// Whether a request may be answered from the cache at all.
+    pub fn is_cacheable_request(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::GET
+            && !headers.contains_key(header::AUTHORIZATION)
+            && !headers.contains_key(header::RANGE)
+    }
+
+    /// Answers a request from the cache, calling `forward` to reach the
+    /// worker on a miss or to revalidate a stale entry.
+    pub async fn fetch<F, Fut>(
+        &self,
+        primary_key: String,
+        request_headers: &HeaderMap,
+        mut grpc_request: HttpRequest,
+        forward: F,
+    ) -> Result<(HttpResponse, CacheStatus), tonic::Status>
+    where
+        F: FnOnce(HttpRequest) -> Fut,
+        Fut: Future<Output = Result<HttpResponse, tonic::Status>>,
+    {
+        let request_directives = directives(request_headers);
+        if request_directives.contains_key("no-store") {
+            return Ok((forward(grpc_request).await?, CacheStatus::Bypass));
+        }
+        let client_revalidates = pragma_no_cache(request_headers);
+
+        let key = self.variant_key(&primary_key, request_headers);
+        let cached = match &key {
+            Some(key) => self.lookup(key).await,
+            None => None,
+        };
+
+        let cached = match cached {
+            Some((meta, response))
+                if !client_revalidates && satisfies(&meta, &request_directives, &response) =>
+            {
+                let response = with_age(response, meta.age());
+                return Ok((
+                    answer_conditional(request_headers, response),
+                    CacheStatus::Hit,
+                ));
+            }
+            Some((_, response)) => {
+                let cached_headers = to_http_headers(response.headers.clone());
+                if add_validators(&mut grpc_request, &cached_headers) {
+                    Some(response)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        let worker_response = forward(grpc_request).await?;
+
+        if let Some(mut cached) = cached {
+            if worker_response.status == StatusCode::NOT_MODIFIED.as_u16() as i32 {
+                merge_headers(&mut cached, worker_response);
+                let cached = self.store(primary_key, request_headers, cached).await;
+                return Ok((
+                    answer_conditional(request_headers, cached),
+                    CacheStatus::Revalidated,
+                ));
+            }
+        }
+
+        let worker_response = self
+            .store(primary_key, request_headers, worker_response)
+            .await;
+        Ok((worker_response, CacheStatus::Miss))
+    }
+
+    /// Drops every stored variant of a URI, after an unsafe method changed it.
+    pub async fn invalidate(&self, primary_key: &str) {
+        let mut removed_files = Vec::new();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let Some(variants) = inner.variants.remove(primary_key) else {
+                return;
+            };
+            for key in variants.keys {
+                if let Some(entry) = inner.memory.pop(&key) {
+                    inner.memory_used -= entry.meta.size;
+                }
+                if let Some(entry) = inner.disk.pop(&key) {
+                    inner.disk_used -= entry.meta.size;
+                    removed_files.push(entry.path);
+                }
+            }
+        }
+        remove_files(removed_files).await;
+    }
+
+    // Key of the variant selected by the request, None when nothing has been
+    // stored for this URI yet.
+    fn variant_key(&self, primary_key: &str, request_headers: &HeaderMap) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .variants
+            .get(primary_key)
+            .map(|variants| variant_key(primary_key, &variants.vary, request_headers))
+    }
+
+    async fn lookup(&self, key: &str) -> Option<(EntryMeta, HttpResponse)> {
+        let disk_entry = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(entry) = inner.memory.get(key) {
+                return Some((entry.meta.clone(), entry.response.clone()));
+            }
+            let entry = inner.disk.pop(key)?;
+            inner.disk_used -= entry.meta.size;
+            entry
+        };
+
+        // Promote the entry from disk back to memory
+        let bytes = tokio::fs::read(&disk_entry.path).await.ok();
+        let _ = tokio::fs::remove_file(&disk_entry.path).await;
+        let response = HttpResponse::decode(bytes?.as_slice()).ok()?;
+
+        let spilled = {
+            let mut inner = self.inner.lock().unwrap();
+            let meta = disk_entry.meta.clone();
+            inner.memory_used += meta.size;
+            inner.memory.put(
+                key.to_string(),
+                Entry {
+                    meta,
+                    response: response.clone(),
+                },
+            );
+            self.evict_memory(&mut inner)
+        };
+        self.write_spilled(spilled).await;
+
+        Some((disk_entry.meta, response))
+    }
+
+    // Stores the response when it is cacheable, and returns it unchanged.
+    async fn store(
+        &self,
+        primary_key: String,
+        request_headers: &HeaderMap,
+        response: HttpResponse,
+    ) -> HttpResponse {
+        let response_headers = to_http_headers(response.headers.clone());
+        let Some((freshness, initial_age)) = freshness(response.status, &response_headers) else {
+            return response;
+        };
+        let vary = vary_names(&response_headers);
+
+        let key = variant_key(&primary_key, &vary, request_headers);
+        let size = key.len() + response.encoded_len();
+        if size > self.config.max_entry_size {
+            return response;
+        }
+
+        let mut removed_files = Vec::new();
+        let spilled = {
+            let mut inner = self.inner.lock().unwrap();
+
+            let variants = inner.variants.entry(primary_key).or_default();
+            if variants.vary != vary {
+                // The worker changed the Vary set, older variants are unreachable
+                variants.keys.clear();
+                variants.vary = vary;
+            }
+            variants.keys.insert(key.clone());
+
+            let entry = Entry {
+                meta: EntryMeta {
+                    stored_at: Instant::now(),
+                    initial_age,
+                    freshness,
+                    size,
+                },
+                response: response.clone(),
+            };
+            if let Some(old) = inner.memory.put(key.clone(), entry) {
+                inner.memory_used -= old.meta.size;
+            }
+            inner.memory_used += size;
+            if let Some(old) = inner.disk.pop(&key) {
+                inner.disk_used -= old.meta.size;
+                removed_files.push(old.path);
+            }
+            self.evict_memory(&mut inner)
+        };
+        remove_files(removed_files).await;
+        self.write_spilled(spilled).await;
+
+        response
+    }
+
+    // Removes least recently used entries until memory is under the cap.
+    fn evict_memory(&self, inner: &mut Inner) -> Vec<(String, Entry)> {
+        let mut evicted = Vec::new();
+        while inner.memory_used > self.config.max_memory {
+            let Some((key, entry)) = inner.memory.pop_lru() else {
+                break;
+            };
+            inner.memory_used -= entry.meta.size;
+            evicted.push((key, entry));
+        }
+        if self.config.disk_path.is_none() {
+            for (key, _) in &evicted {
+                forget_key(inner, key);
+            }
+            evicted.clear();
+        }
+        evicted
+    }
+
+    // Moves entries evicted from memory to disk, outside of the lock.
+    async fn write_spilled(&self, spilled: Vec<(String, Entry)>) {
+        let Some(disk_path) = &self.config.disk_path else {
+            return;
+        };
+
+        for (key, entry) in spilled {
+            if entry.meta.size > self.config.max_disk_size {
+                forget_key(&mut self.inner.lock().unwrap(), &key);
+                continue;
+            }
+
+            let file_id = {
+                let mut inner = self.inner.lock().unwrap();
+                inner.next_file += 1;
+                inner.next_file
+            };
+            let path = disk_path.join(format!("{}.entry", file_id));
+            if let Err(e) = tokio::fs::write(&path, entry.response.encode_to_vec()).await {
+                eprintln!("cache write error: {}: {}", path.display(), e);
+                forget_key(&mut self.inner.lock().unwrap(), &key);
+                continue;
+            }
+
+            let mut removed_files = Vec::new();
+            {
+                let mut inner = self.inner.lock().unwrap();
+                inner.disk_used += entry.meta.size;
+                inner.disk.put(
+                    key,
+                    DiskEntry {
+                        meta: entry.meta,
+                        path,
+                    },
+                );
+                while inner.disk_used > self.config.max_disk_size {
+                    let Some((key, entry)) = inner.disk.pop_lru() else {
+                        break;
+                    };
+                    inner.disk_used -= entry.meta.size;
+                    forget_key(&mut inner, &key);
+                    removed_files.push(entry.path);
+                }
+            }
+            remove_files(removed_files).await;
+        }
+    }
+}
+
+// Deletes the files of entries dropped from the disk tier.
+async fn remove_files(paths: Vec<PathBuf>) {
+    for path in paths {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+// Keys are "<primary key>\0<values of the Vary headers>"
+fn variant_key(primary_key: &str, vary: &[HeaderName], request_headers: &HeaderMap) -> String {
+    let mut key = primary_key.to_string();
+    key.push('\0');
+    for name in vary {
+        let values: Vec<&str> = request_headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        key.push_str(&values.join(","));
+        key.push('\0');
+    }
+    key
+}
+
+fn forget_key(inner: &mut Inner, key: &str) {
+    let primary_key = key.split('\0').next().unwrap_or_default();
+    if let Some(variants) = inner.variants.get_mut(primary_key) {
+        variants.keys.remove(key);
+        if variants.keys.is_empty() {
+            inner.variants.remove(primary_key);
+        }
+    }
+}
+
+// Cache-Control directives, with lowercase names and unquoted values.
+pub fn directives(headers: &HeaderMap) -> HashMap<String, Option<String>> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next()?.trim().to_ascii_lowercase();
+            if name.is_empty() {
+                return None;
+            }
+            let value = parts.next().map(|v| v.trim().trim_matches('"').to_string());
+            Some((name, value))
+        })
+        .collect()
+}
+
+fn pragma_no_cache(headers: &HeaderMap) -> bool {
+    !headers.contains_key(header::CACHE_CONTROL)
+        && headers
+            .get_all(header::PRAGMA)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("no-cache"))
+}
+
+pub fn vary_names(headers: &HeaderMap) -> Vec<HeaderName> {
+    let mut names: Vec<HeaderName> = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    names
+}
+
+fn header_seconds(value: Option<&String>) -> Option<Duration> {
+    value?.parse::<u64>().ok().map(Duration::from_secs)
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+// Freshness lifetime and age of a response when it may be stored, following
+// RFC 9111 sections 3 and 4.2.
+fn freshness(status: i32, headers: &HeaderMap) -> Option<(Duration, Duration)> {
+    let status = u16::try_from(status).ok()?;
+    if !CACHEABLE_STATUS.contains(&status) {
+        return None;
+    }
+
+    let response_directives = directives(headers);
+    if response_directives.contains_key("no-store")
+        || response_directives.contains_key("private")
+        || headers.contains_key(header::SET_COOKIE)
+    {
+        return None;
+    }
+    if vary_names(headers).iter().any(|name| name.as_str() == "*") {
+        return None;
+    }
+
+    let now = SystemTime::now();
+    let date = header_date(headers, header::DATE).unwrap_or(now);
+
+    let lifetime = if response_directives.contains_key("no-cache") {
+        Duration::ZERO
+    } else if let Some(s_maxage) =
+        header_seconds(response_directives.get("s-maxage").and_then(|v| v.as_ref()))
+    {
+        s_maxage
+    } else if let Some(max_age) =
+        header_seconds(response_directives.get("max-age").and_then(|v| v.as_ref()))
+    {
+        max_age
+    } else if let Some(expires) = header_date(headers, header::EXPIRES) {
+        expires.duration_since(date).unwrap_or_default()
+    } else {
+        Duration::ZERO
+    };
+
+    let has_validator =
+        headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+    if lifetime.is_zero() && !has_validator {
+        return None;
+    }
+
+    let apparent_age = now.duration_since(date).unwrap_or_default();
+    let age_value = headers
+        .get(header::AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    Some((lifetime, apparent_age.max(age_value)))
+}
+
+// Whether a stored response may answer a request without revalidation,
+// given the request directives (RFC 9111 section 5.2.1): max-age caps its
+// age, min-fresh asks for freshness left, and max-stale accepts it stale
+// unless the worker required revalidation.
+fn satisfies(
+    meta: &EntryMeta,
+    request_directives: &HashMap<String, Option<String>>,
+    response: &HttpResponse,
+) -> bool {
+    if request_directives.contains_key("no-cache") {
+        return false;
+    }
+    let age = meta.age();
+    let seconds = |name| header_seconds(request_directives.get(name).and_then(|v| v.as_ref()));
+    if seconds("max-age").is_some_and(|max_age| age > max_age) {
+        return false;
+    }
+    let age = age + seconds("min-fresh").unwrap_or_default();
+    if age < meta.freshness {
+        return true;
+    }
+
+    let Some(max_stale) = request_directives.get("max-stale") else {
+        return false;
+    };
+    let response_directives = directives(&to_http_headers(response.headers.clone()));
+    let must_revalidate = [
+        "no-cache",
+        "must-revalidate",
+        "proxy-revalidate",
+        "s-maxage",
+    ]
+    .iter()
+    .any(|name| response_directives.contains_key(*name));
+    if must_revalidate {
+        return false;
+    }
+    match max_stale {
+        // Any staleness without a value
+        None => true,
+        Some(_) => seconds("max-stale").is_some_and(|max_stale| age - meta.freshness <= max_stale),
+    }
+}
+
+// Turns a request into a conditional one using the stored validators.
+// Returns false when the entry has none and cannot be revalidated.
+fn add_validators(grpc_request: &mut HttpRequest, cached_headers: &HeaderMap) -> bool {
+    let etag = cached_headers.get(header::ETAG);
+    let last_modified = cached_headers.get(header::LAST_MODIFIED);
+    if etag.is_none() && last_modified.is_none() {
+        return false;
+    }
+
+    let mut headers = to_http_headers(std::mem::take(&mut grpc_request.headers));
+    headers.remove(header::IF_NONE_MATCH);
+    headers.remove(header::IF_MODIFIED_SINCE);
+    headers.remove(header::IF_MATCH);
+    headers.remove(header::IF_UNMODIFIED_SINCE);
+    if let Some(etag) = etag {
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+    }
+    if let Some(last_modified) = last_modified {
+        headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+    }
+    grpc_request.headers = to_grpc_headers(&headers);
+    true
+}
+
+// Updates a stored response with the headers of a 304 (RFC 9111 section 4.3.4).
+fn merge_headers(cached: &mut HttpResponse, not_modified: HttpResponse) {
+    let mut headers = to_http_headers(std::mem::take(&mut cached.headers));
+    let updates = to_http_headers(not_modified.headers);
+    for name in updates.keys() {
+        if name == header::CONTENT_LENGTH {
+            continue;
+        }
+        headers.remove(name);
+        for value in updates.get_all(name) {
+            headers.append(name, value.clone());
+        }
+    }
+    cached.headers = to_grpc_headers(&headers);
+}
+
+fn with_age(mut response: HttpResponse, age: Duration) -> HttpResponse {
+    let mut headers = to_http_headers(std::mem::take(&mut response.headers));
+    headers.insert(header::AGE, HeaderValue::from(age.as_secs()));
+    response.headers = to_grpc_headers(&headers);
+    response
+}
+
+// Answers the client's own If-None-Match from a stored response.
+fn answer_conditional(request_headers: &HeaderMap, response: HttpResponse) -> HttpResponse {
+    if response.status != StatusCode::OK.as_u16() as i32 {
+        return response;
+    }
+    let headers = to_http_headers(response.headers.clone());
+    let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok()) else {
+        return response;
+    };
+    let etag = etag.trim_start_matches("W/");
+
+    let matches = request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    if !matches {
+        return response;
+    }
+
+    let mut headers = headers;
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(header::CONTENT_TYPE);
+    HttpResponse {
+        version: response.version,
+        status: StatusCode::NOT_MODIFIED.as_u16() as i32,
+        headers: to_grpc_headers(&headers),
+        body: Vec::new(),
+        trailers: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn response(status: u16, pairs: &[(&str, &str)], body: &str) -> HttpResponse {
+        HttpResponse {
+            version: "HTTP/1.1".to_string(),
+            status: status as i32,
+            headers: to_grpc_headers(&headers(pairs)),
+            body: body.as_bytes().to_vec(),
+            trailers: Vec::new(),
+        }
+    }
+
+    fn header(response: &HttpResponse, name: &str) -> Option<String> {
+        to_http_headers(response.headers.clone())
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    // Fetches through the cache, a worker call answering with `response`.
+    // Also returns the headers the worker got, None when it was not called.
+    async fn fetch(
+        cache: &ResponseCache,
+        request: &[(&str, &str)],
+        response: HttpResponse,
+    ) -> (HttpResponse, CacheStatus, Option<HeaderMap>) {
+        fetch_uri(cache, "/page", request, response).await
+    }
+
+    async fn fetch_uri(
+        cache: &ResponseCache,
+        uri: &str,
+        request: &[(&str, &str)],
+        response: HttpResponse,
+    ) -> (HttpResponse, CacheStatus, Option<HeaderMap>) {
+        let request_headers = headers(request);
+        let grpc_request = HttpRequest {
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            headers: to_grpc_headers(&request_headers),
+            ..Default::default()
+        };
+        let sent = Mutex::new(None);
+        let (response, status) = cache
+            .fetch(
+                format!("GET example.com{}", uri),
+                &request_headers,
+                grpc_request,
+                |grpc_request| {
+                    *sent.lock().unwrap() = Some(to_http_headers(grpc_request.headers));
+                    async move { Ok(response) }
+                },
+            )
+            .await
+            .unwrap();
+        (response, status, sent.into_inner().unwrap())
+    }
+
+    // A cache holding `stored`
+    async fn cache_with(stored: HttpResponse) -> ResponseCache {
+        let cache = ResponseCache::new(CacheConfig::default()).unwrap();
+        assert_eq!(fetch(&cache, &[], stored).await.1, CacheStatus::Miss);
+        cache
+    }
+
+    #[test]
+    fn freshness_lifetime() {
+        let lifetime = |pairs: &[(&str, &str)]| freshness(200, &headers(pairs)).map(|(f, _)| f);
+        let secs = Duration::from_secs;
+
+        assert_eq!(lifetime(&[("cache-control", "max-age=60")]), Some(secs(60)));
+        assert_eq!(
+            lifetime(&[("cache-control", "max-age=60, s-maxage=10")]),
+            Some(secs(10))
+        );
+        assert_eq!(
+            lifetime(&[
+                ("date", "Mon, 19 Oct 2026 10:00:00 GMT"),
+                ("expires", "Mon, 19 Oct 2026 10:05:00 GMT"),
+            ]),
+            Some(secs(300))
+        );
+        // Without a lifetime, only worth storing with a validator
+        assert_eq!(lifetime(&[]), None);
+        assert_eq!(lifetime(&[("etag", "\"v1\"")]), Some(Duration::ZERO));
+        assert_eq!(
+            lifetime(&[
+                ("cache-control", "no-cache, max-age=60"),
+                ("etag", "\"v1\"")
+            ]),
+            Some(Duration::ZERO)
+        );
+
+        for pairs in [
+            [("cache-control", "max-age=60, no-store")],
+            [("cache-control", "max-age=60, private")],
+            [("vary", "*")],
+        ] {
+            assert_eq!(lifetime(&pairs), None, "{:?}", pairs);
+        }
+        let with_cookie = [("cache-control", "max-age=60"), ("set-cookie", "a=b")];
+        assert_eq!(lifetime(&with_cookie), None);
+        assert!(freshness(500, &headers(&[("cache-control", "max-age=60")])).is_none());
+
+        let (_, age) = freshness(
+            200,
+            &headers(&[("cache-control", "max-age=60"), ("age", "20")]),
+        )
+        .unwrap();
+        assert_eq!(age, secs(20));
+    }
+
+    #[tokio::test]
+    async fn serves_fresh_entries() {
+        let cache = cache_with(response(200, &[("cache-control", "max-age=60")], "a")).await;
+
+        let (res, status, sent) = fetch(&cache, &[], response(200, &[], "b")).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert!(sent.is_none());
+        assert_eq!(res.body, b"a");
+        assert_eq!(header(&res, "age").as_deref(), Some("0"));
+    }
+
+    #[tokio::test]
+    async fn varies_on_request_headers() {
+        let cache = ResponseCache::new(CacheConfig::default()).unwrap();
+        let varying = |body| {
+            response(
+                200,
+                &[("cache-control", "max-age=60"), ("vary", "Accept-Language")],
+                body,
+            )
+        };
+
+        let en = [("accept-language", "en")];
+        let fr = [("accept-language", "fr")];
+        assert_eq!(fetch(&cache, &en, varying("en")).await.1, CacheStatus::Miss);
+        assert_eq!(fetch(&cache, &fr, varying("fr")).await.1, CacheStatus::Miss);
+
+        let (res, status, _) = fetch(&cache, &en, varying("other")).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(res.body, b"en");
+        let (res, status, _) = fetch(&cache, &fr, varying("other")).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(res.body, b"fr");
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_entries() {
+        let stored = response(
+            200,
+            &[("cache-control", "max-age=0"), ("etag", "\"v1\"")],
+            "a",
+        );
+        let cache = cache_with(stored).await;
+
+        let not_modified = response(
+            304,
+            &[("cache-control", "max-age=60"), ("etag", "\"v1\"")],
+            "",
+        );
+        let (res, status, sent) = fetch(&cache, &[], not_modified).await;
+        assert_eq!(status, CacheStatus::Revalidated);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"a");
+        let sent = sent.unwrap();
+        assert_eq!(sent.get(header::IF_NONE_MATCH).unwrap(), "\"v1\"");
+
+        // The 304 made the entry fresh again
+        let (res, status, _) = fetch(&cache, &[], response(200, &[], "b")).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(res.body, b"a");
+        assert_eq!(header(&res, "cache-control").as_deref(), Some("max-age=60"));
+    }
+
+    #[tokio::test]
+    async fn answers_conditional_requests() {
+        let stored = response(
+            200,
+            &[("cache-control", "max-age=60"), ("etag", "\"v1\"")],
+            "a",
+        );
+        let cache = cache_with(stored).await;
+
+        let (res, status, _) = fetch(
+            &cache,
+            &[("if-none-match", "W/\"v1\"")],
+            response(200, &[], ""),
+        )
+        .await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(res.status, 304);
+        assert!(res.body.is_empty());
+
+        let (res, _, _) = fetch(
+            &cache,
+            &[("if-none-match", "\"v2\"")],
+            response(200, &[], ""),
+        )
+        .await;
+        assert_eq!(res.status, 200);
+    }
+
+    #[tokio::test]
+    async fn request_directives() {
+        let fresh = || response(200, &[("cache-control", "max-age=60")], "cached");
+        // 10 seconds stale as soon as it is stored
+        let stale = |directives: &str| {
+            let cache_control = format!("max-age=10{}", directives);
+            response(
+                200,
+                &[("cache-control", &cache_control), ("age", "20")],
+                "cached",
+            )
+        };
+        let cases = [
+            (fresh(), "", true),
+            (fresh(), "no-cache", false),
+            (fresh(), "no-store", false),
+            (fresh(), "max-age=0", false),
+            (fresh(), "max-age=30", true),
+            (fresh(), "min-fresh=30", true),
+            (fresh(), "min-fresh=120", false),
+            (stale(""), "", false),
+            (stale(""), "max-stale", true),
+            (stale(""), "max-stale=30", true),
+            (stale(""), "max-stale=5", false),
+            (stale(""), "max-stale, max-age=15", false),
+            (stale(", must-revalidate"), "max-stale", false),
+            (stale(", s-maxage=10"), "max-stale", false),
+        ];
+        for (stored, request, cached) in cases {
+            let cache = cache_with(stored.clone()).await;
+            let request: Vec<(&str, &str)> = if request.is_empty() {
+                Vec::new()
+            } else {
+                vec![("cache-control", request)]
+            };
+            let (res, _, sent) = fetch(&cache, &request, response(200, &[], "worker")).await;
+            assert_eq!(
+                res.body == b"cached",
+                cached,
+                "{:?} for {:?}",
+                request,
+                header(&stored, "cache-control")
+            );
+            assert_eq!(sent.is_none(), cached);
+        }
+    }
+
+    #[tokio::test]
+    async fn pragma_no_cache_without_cache_control() {
+        let cache = cache_with(response(200, &[("cache-control", "max-age=60")], "a")).await;
+
+        let (_, _, sent) = fetch(&cache, &[("pragma", "no-cache")], response(200, &[], "b")).await;
+        assert!(sent.is_some());
+        let request = [("pragma", "no-cache"), ("cache-control", "max-age=60")];
+        let (_, _, sent) = fetch(&cache, &request, response(200, &[], "c")).await;
+        assert!(sent.is_none());
+    }
+
+    #[tokio::test]
+    async fn invalidates_every_variant() {
+        let cache = cache_with(response(200, &[("cache-control", "max-age=60")], "a")).await;
+        cache.invalidate("GET example.com/page").await;
+
+        let (res, status, _) = fetch(&cache, &[], response(200, &[], "b")).await;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(res.body, b"b");
+    }
+
+    // Fresh responses of the same size for /a, /b, /c...
+    fn page(body: &str) -> HttpResponse {
+        response(200, &[("cache-control", "max-age=60")], body)
+    }
+
+    fn entry_size(uri: &str, response: &HttpResponse) -> usize {
+        let primary_key = format!("GET example.com{}", uri);
+        variant_key(&primary_key, &[], &HeaderMap::new()).len() + response.encoded_len()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ms-gateway-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn entry_files(dir: &PathBuf) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    async fn status(cache: &ResponseCache, uri: &str) -> CacheStatus {
+        fetch_uri(cache, uri, &[], page("x")).await.1
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let size = entry_size("/a", &page("a"));
+        let cache = ResponseCache::new(CacheConfig {
+            max_memory: size * 5 / 2,
+            ..Default::default()
+        })
+        .unwrap();
+
+        fetch_uri(&cache, "/a", &[], page("a")).await;
+        fetch_uri(&cache, "/b", &[], page("b")).await;
+        // /a becomes the most recently used
+        assert_eq!(status(&cache, "/a").await, CacheStatus::Hit);
+        fetch_uri(&cache, "/c", &[], page("c")).await;
+
+        {
+            let inner = cache.inner.lock().unwrap();
+            assert_eq!(inner.memory.len(), 2);
+            assert_eq!(inner.memory_used, 2 * size);
+            assert!(!inner.variants.contains_key("GET example.com/b"));
+        }
+        assert_eq!(status(&cache, "/a").await, CacheStatus::Hit);
+        assert_eq!(status(&cache, "/c").await, CacheStatus::Hit);
+        assert_eq!(status(&cache, "/b").await, CacheStatus::Miss);
+    }
+
+    #[tokio::test]
+    async fn skips_oversized_entries() {
+        let cache = ResponseCache::new(CacheConfig {
+            max_entry_size: 100,
+            ..Default::default()
+        })
+        .unwrap();
+
+        fetch_uri(&cache, "/big", &[], page(&"x".repeat(200))).await;
+        assert_eq!(cache.inner.lock().unwrap().memory_used, 0);
+        assert_eq!(status(&cache, "/big").await, CacheStatus::Miss);
+    }
+
+    #[tokio::test]
+    async fn spills_to_disk_and_promotes_back() {
+        let dir = temp_dir("spill");
+        let size = entry_size("/a", &page("a"));
+        let cache = ResponseCache::new(CacheConfig {
+            max_memory: size * 3 / 2,
+            disk_path: Some(dir.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        fetch_uri(&cache, "/a", &[], page("a")).await;
+        fetch_uri(&cache, "/b", &[], page("b")).await;
+        {
+            let inner = cache.inner.lock().unwrap();
+            assert!(inner.disk.contains("GET example.com/a\0"));
+            assert_eq!((inner.memory_used, inner.disk_used), (size, size));
+        }
+        assert_eq!(entry_files(&dir), 1);
+
+        // Reading /a brings it back to memory and pushes /b to disk
+        let (res, status, sent) = fetch_uri(&cache, "/a", &[], page("x")).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert!(sent.is_none());
+        assert_eq!(res.body, b"a");
+        {
+            let inner = cache.inner.lock().unwrap();
+            assert!(inner.memory.contains("GET example.com/a\0"));
+            assert!(inner.disk.contains("GET example.com/b\0"));
+            assert_eq!((inner.memory_used, inner.disk_used), (size, size));
+        }
+        assert_eq!(entry_files(&dir), 1);
+        assert_eq!(fetch_uri(&cache, "/b", &[], page("x")).await.0.body, b"b");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn caps_disk_usage() {
+        let dir = temp_dir("cap");
+        let size = entry_size("/a", &page("a"));
+        let cache = ResponseCache::new(CacheConfig {
+            max_memory: size,
+            disk_path: Some(dir.clone()),
+            max_disk_size: size * 3 / 2,
+            ..Default::default()
+        })
+        .unwrap();
+
+        for (uri, body) in [("/a", "a"), ("/b", "b"), ("/c", "c")] {
+            fetch_uri(&cache, uri, &[], page(body)).await;
+        }
+        {
+            // /a was dropped from disk to make room for /b
+            let inner = cache.inner.lock().unwrap();
+            assert_eq!(inner.disk.len(), 1);
+            assert_eq!(inner.disk_used, size);
+            assert!(!inner.variants.contains_key("GET example.com/a"));
+        }
+        assert_eq!(entry_files(&dir), 1);
+
+        // Too large for the disk tier, dropped on eviction
+        fetch_uri(&cache, "/big", &[], page(&"x".repeat(size))).await;
+        fetch_uri(&cache, "/d", &[], page("d")).await;
+        assert!(!cache
+            .inner
+            .lock()
+            .unwrap()
+            .variants
+            .contains_key("GET example.com/big"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn invalidation_removes_spilled_files() {
+        let dir = temp_dir("invalidate");
+        let size = entry_size("/a", &page("a"));
+        let cache = ResponseCache::new(CacheConfig {
+            max_memory: size,
+            disk_path: Some(dir.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        fetch_uri(&cache, "/a", &[], page("a")).await;
+        fetch_uri(&cache, "/b", &[], page("b")).await;
+        assert_eq!(entry_files(&dir), 1);
+
+        cache.invalidate("GET example.com/a").await;
+        assert_eq!(entry_files(&dir), 0);
+        assert_eq!(cache.inner.lock().unwrap().disk_used, 0);
+        assert_eq!(status(&cache, "/a").await, CacheStatus::Miss);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::Method;
+use tokio::sync::watch;
+
+// httpgrpc - protos
+use protos::httpgrpc::{HttpRequest, HttpResponse};
+
+use crate::cache::{directives, vary_names, ResponseCache};
+use crate::config::CoalescingConfig;
+use crate::convert::to_http_headers;
+
+// Request headers that make otherwise identical requests distinct
+const KEY_HEADERS: [header::HeaderName; 5] = [
+    header::HOST,
+    header::COOKIE,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::CACHE_CONTROL,
+];
+
+// Request headers describing the client, set by `proxy_headers`
+const CLIENT_HEADERS: [HeaderName; 4] = [
+    header::FORWARDED,
+    HeaderName::from_static("x-forwarded-for"),
+    HeaderName::from_static("x-forwarded-proto"),
+    HeaderName::from_static("x-forwarded-host"),
+];
+
+type SharedResult = Option<Result<HttpResponse, tonic::Status>>;
+
+/// Collapses concurrent identical cacheable requests into a single worker
+/// call whose response is shared with every waiting request.
+///
+/// The worker only sees the first request, so requests from different
+/// clients are told apart by their address, scheme, TLS server name and
+/// forwarding headers unless `share_across_clients` is set. Waiters still
+/// get a response made for the peer port and connection id of the first
+/// request.
+#[derive(Debug)]
+pub struct Coalescer {
+    config: CoalescingConfig,
+    in_flight: Mutex<HashMap<String, InFlight>>,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    // Headers of the request actually sent, to check Vary for the waiters
+    leader_headers: HeaderMap,
+    result: watch::Receiver<SharedResult>,
+    waiters: usize,
+}
+
+enum Role {
+    Leader(watch::Sender<SharedResult>),
+    Waiter(HeaderMap, watch::Receiver<SharedResult>),
+    Alone,
+}
+
+// Removes the in-flight entry when the leader finishes or is cancelled, so
+// waiters never wait for a call that is gone.
+struct LeaderGuard<'a> {
+    coalescer: &'a Coalescer,
+    key: &'a str,
+}
+
+impl Drop for LeaderGuard<'_> {
+    fn drop(&mut self) {
+        self.coalescer.in_flight.lock().unwrap().remove(self.key);
+    }
+}
+
+impl Coalescer {
+    pub fn new(config: CoalescingConfig) -> Coalescer {
+        Coalescer {
+            config,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends the request with `forward`, or waits for an identical request
+    /// already in flight and reuses its response.
+    pub async fn run<F, Fut>(
+        &self,
+        grpc_request: HttpRequest,
+        forward: F,
+    ) -> Result<HttpResponse, tonic::Status>
+    where
+        F: FnOnce(HttpRequest) -> Fut,
+        Fut: Future<Output = Result<HttpResponse, tonic::Status>>,
+    {
+        let request_headers = to_http_headers(grpc_request.headers.clone());
+        let method = grpc_request.method.parse::<Method>().unwrap_or_default();
+        if !ResponseCache::is_cacheable_request(&method, &request_headers)
+            || !grpc_request.body.is_empty()
+        {
+            return forward(grpc_request).await;
+        }
+
+        let key = coalescing_key(
+            &grpc_request,
+            &request_headers,
+            !self.config.share_across_clients,
+        );
+        match self.role(&key, &request_headers) {
+            Role::Leader(sender) => {
+                let _guard = LeaderGuard {
+                    coalescer: self,
+                    key: &key,
+                };
+                let result = forward(grpc_request).await;
+                let _ = sender.send(Some(result.clone()));
+                result
+            }
+            Role::Waiter(leader_headers, mut receiver) => {
+                let timeout = Duration::from_millis(self.config.timeout_ms);
+                let shared = tokio::time::timeout(timeout, receiver.wait_for(Option::is_some))
+                    .await
+                    .ok()
+                    .and_then(|result| result.ok().and_then(|shared| shared.clone()));
+
+                match shared {
+                    Some(Ok(response))
+                        if is_shareable(&response, &leader_headers, &request_headers) =>
+                    {
+                        Ok(response)
+                    }
+                    // Timed out, leader cancelled, failed or not shareable
+                    _ => forward(grpc_request).await,
+                }
+            }
+            Role::Alone => forward(grpc_request).await,
+        }
+    }
+
+    fn role(&self, key: &str, request_headers: &HeaderMap) -> Role {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(entry) = in_flight.get_mut(key) {
+            if entry.waiters >= self.config.max_waiters {
+                return Role::Alone;
+            }
+            entry.waiters += 1;
+            return Role::Waiter(entry.leader_headers.clone(), entry.result.clone());
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        in_flight.insert(
+            key.to_string(),
+            InFlight {
+                leader_headers: request_headers.clone(),
+                result: receiver,
+                waiters: 0,
+            },
+        );
+        Role::Leader(sender)
+    }
+}
+
+fn coalescing_key(
+    grpc_request: &HttpRequest,
+    request_headers: &HeaderMap,
+    per_client: bool,
+) -> String {
+    let mut key = format!("{} {}", grpc_request.method, grpc_request.uri);
+    let mut push_headers = |names: &[HeaderName]| {
+        for name in names {
+            key.push('\0');
+            for value in request_headers.get_all(name) {
+                key.push_str(value.to_str().unwrap_or_default());
+                key.push(',');
+            }
+        }
+    };
+    push_headers(&KEY_HEADERS);
+    if per_client {
+        push_headers(&CLIENT_HEADERS);
+        // The port differs on every connection, the client is its address
+        let client = grpc_request
+            .peer_addr
+            .parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| grpc_request.peer_addr.clone());
+        for part in [
+            client.as_str(),
+            &grpc_request.local_addr,
+            &grpc_request.scheme,
+            &grpc_request.tls_sni,
+        ] {
+            key.push('\0');
+            key.push_str(part);
+        }
+    }
+    key
+}
+
+// A response can be handed to another client when a shared cache could
+// store it and the headers it varies on are the same in both requests.
+fn is_shareable(
+    response: &HttpResponse,
+    leader_headers: &HeaderMap,
+    request_headers: &HeaderMap,
+) -> bool {
+    let response_headers = to_http_headers(response.headers.clone());
+    let response_directives = directives(&response_headers);
+    if response_directives.contains_key("private")
+        || response_directives.contains_key("no-store")
+        || response_headers.contains_key(header::SET_COOKIE)
+    {
+        return false;
+    }
+
+    vary_names(&response_headers).iter().all(|name| {
+        name.as_str() != "*"
+            && leader_headers
+                .get_all(name)
+                .iter()
+                .eq(request_headers.get_all(name).iter())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::header::HeaderValue;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::convert::to_grpc_headers;
+
+    fn config() -> CoalescingConfig {
+        CoalescingConfig {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn request(peer_addr: &str, pairs: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            uri: "/page".to_string(),
+            headers: to_grpc_headers(&headers(pairs)),
+            peer_addr: peer_addr.to_string(),
+            local_addr: "10.0.0.1:443".to_string(),
+            scheme: "https".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn client() -> HttpRequest {
+        request("192.0.2.1:40000", &[])
+    }
+
+    fn response(body: &str) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            body: body.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn response_with(pairs: &[(&str, &str)]) -> HttpResponse {
+        HttpResponse {
+            headers: to_grpc_headers(&headers(pairs)),
+            ..response("")
+        }
+    }
+
+    // Waits until `waiters` requests wait on the one call in flight
+    async fn until_waiting(coalescer: &Coalescer, waiters: usize) {
+        loop {
+            let current = coalescer
+                .in_flight
+                .lock()
+                .unwrap()
+                .values()
+                .next()
+                .map(|entry| entry.waiters);
+            if current == Some(waiters) {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn waiters_share_the_leader_response() {
+        let coalescer = Coalescer::new(config());
+        let calls = AtomicUsize::new(0);
+        let (release, released) = oneshot::channel::<()>();
+
+        let leader = coalescer.run(client(), |_| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async {
+                released.await.unwrap();
+                Ok(response("leader"))
+            }
+        });
+        let waiter = || {
+            coalescer.run(client(), |_| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                async { Ok(response("waiter")) }
+            })
+        };
+        let release = async {
+            until_waiting(&coalescer, 2).await;
+            release.send(()).unwrap();
+        };
+
+        let (leader, first, second, ()) = tokio::join!(leader, waiter(), waiter(), release);
+        for result in [leader, first, second] {
+            assert_eq!(result.unwrap().body, b"leader");
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(coalescer.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_releases_waiters() {
+        let coalescer = Coalescer::new(config());
+        let mut leader = Box::pin(coalescer.run(client(), |_| async {
+            std::future::pending::<()>().await;
+            Ok(response("leader"))
+        }));
+        assert!(futures::poll!(&mut leader).is_pending());
+
+        let waiter = coalescer.run(client(), |_| async { Ok(response("waiter")) });
+        let cancel = async {
+            until_waiting(&coalescer, 1).await;
+            drop(leader);
+        };
+        let (waiter, ()) = tokio::join!(waiter, cancel);
+
+        assert_eq!(waiter.unwrap().body, b"waiter");
+        assert!(coalescer.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_leader_is_not_shared() {
+        let coalescer = Coalescer::new(config());
+        let (release, released) = oneshot::channel::<()>();
+
+        let leader = coalescer.run(client(), |_| async {
+            released.await.unwrap();
+            Err(tonic::Status::unavailable("no worker endpoint available"))
+        });
+        let waiter = coalescer.run(client(), |_| async { Ok(response("waiter")) });
+        let release = async {
+            until_waiting(&coalescer, 1).await;
+            release.send(()).unwrap();
+        };
+        let (leader, waiter, ()) = tokio::join!(leader, waiter, release);
+
+        assert!(leader.is_err());
+        assert_eq!(waiter.unwrap().body, b"waiter");
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_max_waiters_go_alone() {
+        let coalescer = Coalescer::new(CoalescingConfig {
+            max_waiters: 1,
+            ..config()
+        });
+        let (release, released) = oneshot::channel::<()>();
+
+        let mut leader = Box::pin(coalescer.run(client(), |_| async {
+            released.await.unwrap();
+            Ok(response("leader"))
+        }));
+        assert!(futures::poll!(&mut leader).is_pending());
+        let mut waiter = Box::pin(coalescer.run(client(), |_| async { Ok(response("waiter")) }));
+        assert!(futures::poll!(&mut waiter).is_pending());
+
+        let alone = coalescer
+            .run(client(), |_| async { Ok(response("alone")) })
+            .await;
+        assert_eq!(alone.unwrap().body, b"alone");
+
+        release.send(()).unwrap();
+        assert_eq!(leader.await.unwrap().body, b"leader");
+        assert_eq!(waiter.await.unwrap().body, b"leader");
+    }
+
+    #[tokio::test]
+    async fn waiters_time_out() {
+        let coalescer = Coalescer::new(CoalescingConfig {
+            timeout_ms: 20,
+            ..config()
+        });
+        let mut leader = Box::pin(coalescer.run(client(), |_| async {
+            std::future::pending::<()>().await;
+            Ok(response("leader"))
+        }));
+        assert!(futures::poll!(&mut leader).is_pending());
+
+        let waiter = coalescer.run(client(), |_| async { Ok(response("waiter")) });
+        let waiter = tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("the waiter did not time out");
+        assert_eq!(waiter.unwrap().body, b"waiter");
+    }
+
+    #[tokio::test]
+    async fn only_cacheable_requests_are_coalesced() {
+        let coalescer = Coalescer::new(config());
+        let mut leader = Box::pin(coalescer.run(client(), |_| async {
+            std::future::pending::<()>().await;
+            Ok(response("leader"))
+        }));
+        assert!(futures::poll!(&mut leader).is_pending());
+
+        let post = HttpRequest {
+            method: "POST".to_string(),
+            ..client()
+        };
+        let with_body = HttpRequest {
+            body: b"query".to_vec(),
+            ..client()
+        };
+        let authorized = request("192.0.2.1:40000", &[("authorization", "Bearer token")]);
+        for request in [post, with_body, authorized] {
+            let result = coalescer
+                .run(request, |_| async { Ok(response("own")) })
+                .await;
+            assert_eq!(result.unwrap().body, b"own");
+        }
+    }
+
+    #[test]
+    fn keys_per_client() {
+        let key = |request: &HttpRequest, per_client| {
+            coalescing_key(
+                request,
+                &to_http_headers(request.headers.clone()),
+                per_client,
+            )
+        };
+        let first = client();
+
+        // Another connection of the same client
+        assert_eq!(
+            key(&first, true),
+            key(&request("192.0.2.1:40001", &[]), true)
+        );
+
+        let others = [
+            request("192.0.2.2:40000", &[]),
+            request("192.0.2.1:40000", &[("x-forwarded-for", "198.51.100.7")]),
+            request("192.0.2.1:40000", &[("forwarded", "for=198.51.100.7")]),
+            HttpRequest {
+                scheme: "http".to_string(),
+                ..client()
+            },
+            HttpRequest {
+                tls_sni: "other.example.com".to_string(),
+                ..client()
+            },
+        ];
+        for other in &others {
+            assert_ne!(key(&first, true), key(other, true), "{:?}", other);
+            assert_eq!(key(&first, false), key(other, false), "{:?}", other);
+        }
+
+        // Never shared across these, whatever the setting
+        for other in [
+            request("192.0.2.1:40000", &[("cookie", "session=1")]),
+            request("192.0.2.1:40000", &[("host", "other.example.com")]),
+            HttpRequest {
+                uri: "/other".to_string(),
+                ..client()
+            },
+        ] {
+            assert_ne!(key(&first, false), key(&other, false), "{:?}", other);
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_share_calls_only_when_configured() {
+        for share_across_clients in [false, true] {
+            let coalescer = Coalescer::new(CoalescingConfig {
+                share_across_clients,
+                ..config()
+            });
+            let (release, released) = oneshot::channel::<()>();
+
+            let mut leader = Box::pin(coalescer.run(client(), |_| async {
+                released.await.unwrap();
+                Ok(response("leader"))
+            }));
+            assert!(futures::poll!(&mut leader).is_pending());
+            let mut other = Box::pin(coalescer.run(request("192.0.2.2:40000", &[]), |_| async {
+                Ok(response("other"))
+            }));
+            let polled = futures::poll!(&mut other);
+
+            release.send(()).unwrap();
+            leader.await.unwrap();
+            let other = match polled {
+                std::task::Poll::Ready(other) => {
+                    // A call of its own, answered straight away
+                    assert!(!share_across_clients);
+                    other
+                }
+                std::task::Poll::Pending => {
+                    assert!(share_across_clients);
+                    other.await
+                }
+            };
+            let expected: &[u8] = if share_across_clients {
+                b"leader"
+            } else {
+                b"other"
+            };
+            assert_eq!(other.unwrap().body, expected);
+        }
+    }
+
+    #[test]
+    fn shareable_responses() {
+        let leader = headers(&[("accept-language", "en")]);
+        let same = leader.clone();
+        let other = headers(&[("accept-language", "fr")]);
+
+        assert!(is_shareable(&response("a"), &leader, &other));
+        let varying = response_with(&[("vary", "Accept-Language")]);
+        assert!(is_shareable(&varying, &leader, &same));
+        assert!(!is_shareable(&varying, &leader, &other));
+        assert!(!is_shareable(
+            &response_with(&[("vary", "*")]),
+            &leader,
+            &same
+        ));
+        for headers in [
+            [("cache-control", "private")],
+            [("cache-control", "no-store")],
+            [("set-cookie", "session=1")],
+        ] {
+            assert!(
+                !is_shareable(&response_with(&headers), &leader, &same),
+                "{:?}",
+                headers
+            );
+        }
+    }
+}
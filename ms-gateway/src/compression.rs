@@ -0,0 +1,515 @@
+use std::io::{Read, Write};
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+use serde::Deserialize;
+
+use crate::config::{CompressionConfig, RequestDecompressionConfig};
+use crate::sse;
+
+/// Content codings supported by the executor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Br => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Br),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Picks the encoding to use for a client sending `accept_encoding`.
+///
+/// The client's q-values decide first; ties go to the order of `supported`.
+pub fn negotiate(accept_encoding: &HeaderMap, supported: &[Encoding]) -> Option<Encoding> {
+    let mut wildcard_q: Option<f32> = None;
+    let mut explicit: Vec<(String, f32)> = Vec::new();
+
+    for value in accept_encoding.get_all(header::ACCEPT_ENCODING) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for item in value.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if name == "*" {
+                wildcard_q = Some(q);
+            } else {
+                explicit.push((name, q));
+            }
+        }
+    }
+
+    // q=0 means "not acceptable", so only positive values can win
+    let mut best: Option<Encoding> = None;
+    let mut best_q = 0.0;
+    for encoding in supported {
+        let q = explicit
+            .iter()
+            .find(|(name, _)| Encoding::from_name(name) == Some(*encoding))
+            .map(|(_, q)| *q)
+            .or(wildcard_q)
+            .unwrap_or(0.0);
+        if q > best_q {
+            best = Some(*encoding);
+            best_q = q;
+        }
+    }
+    best
+}
+
+pub fn compress(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Br => {
+            let mut output = Vec::new();
+            {
+                // quality 5, window 22: a usual choice for on-the-fly compression
+                let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                encoder.write_all(data)?;
+            }
+            Ok(output)
+        }
+        Encoding::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+    }
+}
+
+/// Reasons a compressed request body cannot be forwarded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// A content coding the executor does not implement.
+    Unsupported(String),
+    /// The decoded body is larger than the configured limit.
+    TooLarge,
+    Invalid(std::io::Error),
+}
+
+impl DecodeError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            DecodeError::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            DecodeError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            DecodeError::Invalid(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Unsupported(name) => write!(f, "unsupported content coding: {}", name),
+            DecodeError::TooLarge => write!(f, "decompressed body exceeds the size limit"),
+            DecodeError::Invalid(e) => write!(f, "invalid compressed body: {}", e),
+        }
+    }
+}
+
+/// Decodes `data`, failing once more than `limit` bytes have been produced
+/// so a small compressed body cannot expand without bounds.
+pub fn decompress(encoding: Encoding, data: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        Encoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(data)),
+        Encoding::Br => Box::new(brotli::Decompressor::new(data, 4096)),
+        Encoding::Zstd => Box::new(zstd::Decoder::new(data).map_err(DecodeError::Invalid)?),
+    };
+
+    let mut output = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(DecodeError::Invalid)?;
+    if output.len() > limit {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(output)
+}
+
+/// Largest request body read when it will be decoded: a body larger
+/// than `max_decompressed_size` is refused before being read whole.
+pub fn encoded_size_limit(config: &RequestDecompressionConfig, headers: &HeaderMap) -> Option<u64> {
+    (config.enabled && headers.contains_key(header::CONTENT_ENCODING))
+        .then_some(config.max_decompressed_size as u64)
+}
+
+/// Decodes a request body sent with `Content-Encoding`, removing the header
+/// so the worker receives plain bytes.
+pub fn decompress_request(
+    config: &RequestDecompressionConfig,
+    headers: &mut HeaderMap,
+    body: Vec<u8>,
+) -> Result<Vec<u8>, DecodeError> {
+    if !config.enabled || !headers.contains_key(header::CONTENT_ENCODING) {
+        return Ok(body);
+    }
+
+    // Codings are listed in the order they were applied
+    let mut codings: Vec<String> = headers
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty() && name != "identity")
+        .collect();
+
+    let mut body = body;
+    while let Some(name) = codings.pop() {
+        let encoding = Encoding::from_name(&name).ok_or(DecodeError::Unsupported(name))?;
+        body = decompress(encoding, &body, config.max_decompressed_size)?;
+    }
+
+    headers.remove(header::CONTENT_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Ok(body)
+}
+
+/// Compresses a worker response body in place with the encoding negotiated
+/// for the request, when the response is eligible.
+pub fn compress_response(
+    config: &CompressionConfig,
+    method: &Method,
+    encoding: Option<Encoding>,
+    status: StatusCode,
+    headers: &mut HeaderMap,
+    body: &mut Vec<u8>,
+) {
+    if !config.enabled || !is_eligible(config, method, status, headers, body) {
+        return;
+    }
+
+    // The representation now depends on Accept-Encoding, whether or not
+    // this particular client gets a compressed body.
+    add_vary(headers);
+
+    let Some(encoding) = encoding else {
+        return;
+    };
+
+    let compressed = match compress(encoding, body) {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            eprintln!("{} compression error: {}", encoding.as_str(), e);
+            return;
+        }
+    };
+    if compressed.len() >= body.len() {
+        return;
+    }
+
+    *body = compressed;
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    headers.remove(header::CONTENT_LENGTH);
+    weaken_etag(headers);
+}
+
+fn is_eligible(
+    config: &CompressionConfig,
+    method: &Method,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> bool {
+    if method == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || status == StatusCode::PARTIAL_CONTENT
+    {
+        return false;
+    }
+    if body.len() < config.min_size {
+        return false;
+    }
+
+    // Already encoded by the worker
+    if headers.contains_key(header::CONTENT_ENCODING) || headers.contains_key(header::CONTENT_RANGE)
+    {
+        return false;
+    }
+
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return false;
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+    match content_type {
+        // Events must reach the client as soon as they are produced
+        Some(content_type) if content_type == sse::EVENT_STREAM => false,
+        Some(content_type) => config
+            .content_types
+            .iter()
+            .any(|allowed| content_type.starts_with(&allowed.to_ascii_lowercase())),
+        None => false,
+    }
+}
+
+fn add_vary(headers: &mut HeaderMap) {
+    let already_varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !already_varies {
+        headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+// A strong validator must change with the bytes sent, so a strong ETag
+// computed by the worker for the identity body is downgraded to weak.
+fn weaken_etag(headers: &mut HeaderMap) {
+    let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    if etag.starts_with("W/") {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!("W/{}", etag)) {
+        headers.insert(header::ETAG, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Encoding; 3] = [Encoding::Zstd, Encoding::Br, Encoding::Gzip];
+
+    fn accept(value: &str) -> Option<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_str(value).unwrap(),
+        );
+        negotiate(&headers, &ALL)
+    }
+
+    fn text_response(body_len: usize) -> (HeaderMap, Vec<u8>) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body_len));
+        headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+        (headers, "hello ".repeat(body_len / 6).into_bytes())
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate(&HeaderMap::new(), &ALL), None);
+        assert_eq!(accept(""), None);
+        // Ties go to the server's preference
+        assert_eq!(accept("gzip, br"), Some(Encoding::Br));
+        assert_eq!(accept("gzip"), Some(Encoding::Gzip));
+        assert_eq!(accept("X-GZIP"), Some(Encoding::Gzip));
+        assert_eq!(accept("deflate, compress"), None);
+    }
+
+    #[test]
+    fn negotiation_q_values() {
+        assert_eq!(accept("zstd;q=0.5, gzip;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(accept("zstd; q=0.5, br ;q=1"), Some(Encoding::Br));
+        assert_eq!(accept("gzip;q=0"), None);
+        assert_eq!(
+            accept("zstd;q=0, br;q=0, gzip;q=0.001"),
+            Some(Encoding::Gzip)
+        );
+        // Explicit entries override the wildcard
+        assert_eq!(accept("*"), Some(Encoding::Zstd));
+        assert_eq!(accept("*;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(accept("*, zstd;q=0"), Some(Encoding::Br));
+        assert_eq!(accept("*;q=0"), None);
+    }
+
+    #[test]
+    fn negotiation_identity() {
+        // Refusing identity does not make other codings acceptable
+        assert_eq!(accept("identity;q=0"), None);
+        assert_eq!(accept("gzip, identity;q=0"), Some(Encoding::Gzip));
+        assert_eq!(accept("*, identity;q=0"), Some(Encoding::Zstd));
+        assert_eq!(accept("identity, gzip;q=0.1"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiation_across_header_lines() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("zstd;q=0.1"),
+        );
+        headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        assert_eq!(negotiate(&headers, &ALL), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&headers, &[Encoding::Br]), None);
+    }
+
+    #[test]
+    fn compresses_eligible_responses() {
+        let config = CompressionConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        for encoding in ALL {
+            let (mut headers, mut body) = text_response(4096);
+            let original = body.clone();
+            compress_response(
+                &config,
+                &Method::GET,
+                Some(encoding),
+                StatusCode::OK,
+                &mut headers,
+                &mut body,
+            );
+
+            assert!(body.len() < original.len());
+            assert_eq!(headers[header::CONTENT_ENCODING], encoding.as_str());
+            assert_eq!(headers[header::VARY], "Accept-Encoding");
+            assert_eq!(headers[header::ETAG], "W/\"v1\"");
+            assert!(!headers.contains_key(header::CONTENT_LENGTH));
+            assert_eq!(decompress(encoding, &body, 4096).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn skips_ineligible_responses() {
+        let config = CompressionConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let compressed = |method: Method, status: StatusCode, edit: fn(&mut HeaderMap)| {
+            let (mut headers, mut body) = text_response(4096);
+            edit(&mut headers);
+            compress_response(
+                &config,
+                &method,
+                Some(Encoding::Gzip),
+                status,
+                &mut headers,
+                &mut body,
+            );
+            headers.contains_key(header::CONTENT_ENCODING)
+        };
+
+        assert!(compressed(Method::GET, StatusCode::OK, |_| ()));
+        assert!(!compressed(Method::HEAD, StatusCode::OK, |_| ()));
+        assert!(!compressed(
+            Method::GET,
+            StatusCode::PARTIAL_CONTENT,
+            |_| ()
+        ));
+        assert!(!compressed(Method::GET, StatusCode::OK, |h| {
+            h.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        }));
+        assert!(!compressed(Method::GET, StatusCode::OK, |h| {
+            h.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(sse::EVENT_STREAM),
+            );
+        }));
+        assert!(!compressed(Method::GET, StatusCode::OK, |h| {
+            h.insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("public, no-transform"),
+            );
+        }));
+
+        // Small bodies are left alone, without a Vary either
+        let (mut headers, mut body) = text_response(100);
+        compress_response(
+            &config,
+            &Method::GET,
+            Some(Encoding::Gzip),
+            StatusCode::OK,
+            &mut headers,
+            &mut body,
+        );
+        assert!(!headers.contains_key(header::VARY));
+    }
+
+    #[test]
+    fn varies_without_an_accepted_encoding() {
+        let config = CompressionConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let (mut headers, mut body) = text_response(4096);
+        headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        compress_response(
+            &config,
+            &Method::GET,
+            None,
+            StatusCode::OK,
+            &mut headers,
+            &mut body,
+        );
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(headers.get_all(header::VARY).iter().count(), 1);
+        assert_eq!(headers[header::ETAG], "\"v1\"");
+    }
+
+    #[test]
+    fn decompression_limit() {
+        let data = vec![b'a'; 1000];
+        let compressed = compress(Encoding::Gzip, &data).unwrap();
+        assert_eq!(decompress(Encoding::Gzip, &compressed, 1000).unwrap(), data);
+        assert!(matches!(
+            decompress(Encoding::Gzip, &compressed, 999),
+            Err(DecodeError::TooLarge)
+        ));
+        assert!(matches!(
+            decompress(Encoding::Gzip, b"not gzip", 1000),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,659 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+
+pub use protos::config::GrpcEncoding;
+
+use crate::compression::Encoding;
+
+// Environment variable used to locate the configuration file when no path
+// is given on the command line.
+const CONFIG_ENV: &str = "MS_EXECUTOR_CONFIG";
+
+/// Executor configuration, loaded from a TOML file.
+///
+/// Every field has a default matching the previous hard-coded behavior, so
+/// running without a configuration file keeps working as before.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the HTTP listener binds to, unless `listeners` is set.
+    pub listen_addr: SocketAddr,
+    /// Listeners accepting client connections, replacing `listen_addr`.
+    pub listeners: Vec<ListenerConfig>,
+    /// gRPC endpoints of the workers, balanced by the executor. Entries are
+    /// URIs or `{ uri = "...", weight = 2 }` tables.
+    pub worker_endpoints: Vec<WorkerEndpointConfig>,
+    pub forwarded: ForwardedConfig,
+    pub compression: CompressionConfig,
+    pub request_decompression: RequestDecompressionConfig,
+    pub grpc: GrpcConfig,
+    pub cache: CacheConfig,
+    pub coalescing: CoalescingConfig,
+    /// CORS policies, the first one matching a request applies.
+    pub cors: Vec<CorsConfig>,
+    pub websocket: WebSocketConfig,
+    pub sse: SseConfig,
+    pub connect: ConnectConfig,
+    pub connections: ConnectionsConfig,
+    pub slow_clients: SlowClientConfig,
+    pub middleware: MiddlewareConfig,
+    pub admin: AdminConfig,
+    pub shutdown: ShutdownConfig,
+    pub health_check: HealthCheckConfig,
+    pub runtime: RuntimeConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            listeners: Vec::new(),
+            worker_endpoints: vec![WorkerEndpointConfig::Uri("http://[::1]:50051".to_string())],
+            forwarded: ForwardedConfig::default(),
+            compression: CompressionConfig::default(),
+            request_decompression: RequestDecompressionConfig::default(),
+            grpc: GrpcConfig::default(),
+            cache: CacheConfig::default(),
+            coalescing: CoalescingConfig::default(),
+            cors: Vec::new(),
+            websocket: WebSocketConfig::default(),
+            sse: SseConfig::default(),
+            connect: ConnectConfig::default(),
+            connections: ConnectionsConfig::default(),
+            slow_clients: SlowClientConfig::default(),
+            middleware: MiddlewareConfig::default(),
+            admin: AdminConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            health_check: HealthCheckConfig::default(),
+            runtime: RuntimeConfig::default(),
+        }
+    }
+}
+
+/// A socket accepting client connections.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Name used in logs, the address by default.
+    pub name: Option<String>,
+    /// TCP address; IPv6 addresses only accept IPv6 so an IPv4 listener
+    /// can use the same port.
+    pub address: Option<SocketAddr>,
+    /// Unix domain socket path, used instead of `address`.
+    pub path: Option<PathBuf>,
+    /// Permissions of the Unix domain socket file, e.g. `0o660`.
+    pub mode: Option<u32>,
+    pub tls: Option<TlsConfig>,
+    /// Path prefixes served on this listener, all paths when empty.
+    pub routes: Vec<String>,
+    /// Expect PROXY protocol headers from trusted peers.
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+}
+
+/// PROXY protocol (v1 and v2) headers sent by an L4 load balancer ahead of
+/// the client's bytes, giving the real client address.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// Peers that must send a header. Other peers are served as direct
+    /// clients. Peers on Unix domain sockets are always trusted.
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub trusted_sources: Vec<IpNet>,
+    /// Time a trusted peer gets to send the header.
+    pub timeout_ms: u64,
+}
+
+impl Default for ProxyProtocolConfig {
+    fn default() -> Self {
+        ProxyProtocolConfig {
+            trusted_sources: Vec::new(),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// Certificate chain and private key, both PEM encoded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum WorkerEndpointConfig {
+    Uri(String),
+    Weighted { uri: String, weight: u32 },
+}
+
+impl WorkerEndpointConfig {
+    pub fn uri(&self) -> &str {
+        match self {
+            WorkerEndpointConfig::Uri(uri) => uri,
+            WorkerEndpointConfig::Weighted { uri, .. } => uri,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        match self {
+            WorkerEndpointConfig::Uri(_) => 1,
+            WorkerEndpointConfig::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+/// Settings for the `Forwarded` and `X-Forwarded-*` request headers.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardedConfig {
+    /// Peers whose incoming forwarding headers are kept and extended.
+    /// Requests from any other peer have those headers replaced.
+    /// Entries are single addresses or CIDR networks.
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Whether peers on Unix domain sockets, usually a local proxy, are
+    /// trusted.
+    pub trust_unix_peers: bool,
+}
+
+impl ForwardedConfig {
+    pub fn is_trusted(&self, addr: IpAddr) -> bool {
+        contains_addr(&self.trusted_proxies, addr)
+    }
+}
+
+/// Compression of worker responses sent to clients.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Encodings offered to clients, most preferred first.
+    pub encodings: Vec<Encoding>,
+    /// Bodies smaller than this many bytes are sent as they are.
+    pub min_size: usize,
+    /// Content types eligible for compression, matched as prefixes.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            encodings: vec![Encoding::Zstd, Encoding::Br, Encoding::Gzip],
+            min_size: 1024,
+            content_types: vec![
+                "text/".to_string(),
+                "application/json".to_string(),
+                "application/javascript".to_string(),
+                "application/xml".to_string(),
+                "image/svg+xml".to_string(),
+            ],
+        }
+    }
+}
+
+/// Decoding of compressed request bodies before they reach the workers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestDecompressionConfig {
+    pub enabled: bool,
+    /// Requests whose decoded body would be larger are refused with 413,
+    /// as are encoded bodies larger than this.
+    pub max_decompressed_size: usize,
+}
+
+impl Default for RequestDecompressionConfig {
+    fn default() -> Self {
+        RequestDecompressionConfig {
+            enabled: false,
+            max_decompressed_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Message compression on the gRPC connections to the workers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Encoding of requests sent to the workers; uncompressed when unset.
+    pub send_compression: Option<GrpcEncoding>,
+    /// Encodings the workers may use for their responses.
+    pub accept_compression: Vec<GrpcEncoding>,
+    /// Requests smaller than this many bytes are sent uncompressed.
+    pub compression_threshold: usize,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            send_compression: None,
+            accept_compression: vec![GrpcEncoding::Gzip, GrpcEncoding::Zstd],
+            compression_threshold: 1024,
+        }
+    }
+}
+
+/// Cache of worker responses to GET requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// Bytes of responses kept in memory before evicting the least
+    /// recently used ones.
+    pub max_memory: usize,
+    /// Larger responses are never stored.
+    pub max_entry_size: usize,
+    /// Directory receiving entries evicted from memory; evicted entries are
+    /// dropped when unset.
+    pub disk_path: Option<PathBuf>,
+    pub max_disk_size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            max_memory: 64 * 1024 * 1024,
+            max_entry_size: 8 * 1024 * 1024,
+            disk_path: None,
+            max_disk_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Collapsing of concurrent identical GET requests into one worker call.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoalescingConfig {
+    pub enabled: bool,
+    /// Requests waiting on one call; further requests go to a worker.
+    pub max_waiters: usize,
+    /// Time a request waits for the shared response before calling a
+    /// worker itself.
+    pub timeout_ms: u64,
+    /// Whether requests from different clients share a call. Workers then
+    /// see the address, TLS and forwarding headers of the first client
+    /// only, so their responses must not depend on them.
+    pub share_across_clients: bool,
+}
+
+impl Default for CoalescingConfig {
+    fn default() -> Self {
+        CoalescingConfig {
+            enabled: false,
+            max_waiters: 1000,
+            timeout_ms: 5000,
+            share_across_clients: false,
+        }
+    }
+}
+
+/// WebSocket connections terminated by the executor, relayed to workers
+/// message by message. Other upgrades are tunneled as raw bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    /// Largest message accepted from a client; larger ones close the
+    /// connection with 1009 (message too big).
+    pub max_message_size: usize,
+    /// Time without a message in either direction before the connection is
+    /// closed with 1001 (going away). 0 disables it.
+    pub idle_timeout_ms: u64,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            enabled: true,
+            max_message_size: 1024 * 1024,
+            idle_timeout_ms: 300000,
+        }
+    }
+}
+
+/// Requests accepting `text/event-stream`, forwarded over the streaming
+/// RPC so every event reaches the client as soon as the worker sends it.
+/// Off unless enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SseConfig {
+    pub enabled: bool,
+    /// Paths serving event streams, matched as prefixes; every path when
+    /// empty.
+    pub path_prefixes: Vec<String>,
+    /// Time without an event after which a comment line is sent, keeping
+    /// intermediaries from closing the connection. 0 disables it.
+    pub heartbeat_interval_ms: u64,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        SseConfig {
+            enabled: false,
+            path_prefixes: Vec::new(),
+            heartbeat_interval_ms: 15000,
+        }
+    }
+}
+
+/// Forward proxying of `CONNECT host:port` requests. Other CONNECT
+/// requests are refused with 405.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectConfig {
+    pub enabled: bool,
+    /// Destinations clients may reach, as "host:port" with '*' matching
+    /// any characters, as in "*.example.com:443". None when empty.
+    pub allowed_destinations: Vec<String>,
+    /// Credentials accepted in `Proxy-Authorization` (Basic). No
+    /// authentication when empty.
+    pub users: Vec<ProxyUserConfig>,
+    /// Tunnel through a worker over the Upgrade RPC instead of connecting
+    /// from the executor.
+    pub via_worker: bool,
+    /// Time the executor gets to connect to the destination.
+    pub connect_timeout_ms: u64,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        ConnectConfig {
+            enabled: false,
+            allowed_destinations: Vec::new(),
+            users: Vec::new(),
+            via_worker: false,
+            connect_timeout_ms: 10000,
+        }
+    }
+}
+
+/// Credentials of a forward proxy user.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyUserConfig {
+    pub username: String,
+    pub password: String,
+}
+
+/// Limits on client connections, shared by all listeners.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionsConfig {
+    /// Connections open at once, no limit when 0.
+    pub max_connections: usize,
+    /// What happens to connections beyond `max_connections`.
+    pub over_limit: OverLimit,
+    /// Time a connection may stay open without a request in flight.
+    /// Disabled when 0.
+    pub idle_timeout_ms: u64,
+    /// Requests served on a keep-alive connection before it is closed, no
+    /// limit when 0.
+    pub max_requests: u64,
+    /// Time after which a connection is closed once its requests complete,
+    /// so clients spread over new processes. Disabled when 0.
+    pub max_age_ms: u64,
+}
+
+impl Default for ConnectionsConfig {
+    fn default() -> Self {
+        ConnectionsConfig {
+            max_connections: 0,
+            over_limit: OverLimit::Pause,
+            idle_timeout_ms: 0,
+            max_requests: 0,
+            max_age_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverLimit {
+    /// Stop accepting until a connection closes; clients wait in the
+    /// listen backlog.
+    #[default]
+    Pause,
+    /// Accept and close straight away.
+    Close,
+}
+
+/// Protection against clients sending or reading too slowly. Offending
+/// connections are closed and counted as slow clients; each limit is
+/// disabled when 0.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowClientConfig {
+    /// Time a client gets to complete the TLS handshake, and an HTTP/1
+    /// client to send the request headers.
+    pub header_read_timeout_ms: u64,
+    /// Time to wait for the next part of a request body, answered with
+    /// 408 once elapsed.
+    pub body_read_timeout_ms: u64,
+    /// Bytes per second a request body must average.
+    pub min_upload_rate: u64,
+    /// Bytes per second the client must read at while responses wait on
+    /// it.
+    pub min_download_rate: u64,
+    /// Time before the transfer rates are enforced.
+    pub min_rate_grace_ms: u64,
+    /// Time a write to the client may stay blocked on it.
+    pub write_timeout_ms: u64,
+    /// Interval of the pings sent to idle HTTP/2 clients.
+    pub http2_keep_alive_interval_ms: u64,
+    /// Time an HTTP/2 client gets to answer a ping, no pings are sent
+    /// when 0.
+    pub http2_keep_alive_timeout_ms: u64,
+}
+
+impl Default for SlowClientConfig {
+    fn default() -> Self {
+        SlowClientConfig {
+            header_read_timeout_ms: 30000,
+            body_read_timeout_ms: 0,
+            min_upload_rate: 0,
+            min_download_rate: 0,
+            min_rate_grace_ms: 10000,
+            write_timeout_ms: 0,
+            http2_keep_alive_interval_ms: 0,
+            http2_keep_alive_timeout_ms: 20000,
+        }
+    }
+}
+
+/// Tower layers around the forwarding of every request, inside the ones
+/// added through the library API. Each is disabled when 0 or false.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiddlewareConfig {
+    /// Requests without a response after this long get 504.
+    pub timeout_ms: u64,
+    /// Requests forwarded at once, others wait for their turn.
+    pub concurrency_limit: usize,
+    /// Requests over `concurrency_limit` get 503 instead of waiting.
+    pub load_shed: bool,
+}
+
+/// CORS policy answered by the executor for a set of hosts and paths.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Hosts the policy applies to, "*" matching any characters; any host
+    /// when empty.
+    pub hosts: Vec<String>,
+    pub path_prefix: String,
+    /// Exact origins or patterns such as "https://*.example.com"; "*" allows
+    /// every origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in preflights; "*" allows any.
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache a preflight response.
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            hosts: Vec::new(),
+            path_prefix: "/".to_string(),
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "HEAD", "POST"].map(String::from).to_vec(),
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+/// HTTP admin API used to inspect and control the executor, off unless
+/// enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    /// Keep it on loopback or a private network, the API is unauthenticated.
+    pub listen_addr: SocketAddr,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3001)),
+        }
+    }
+}
+
+/// Sequence followed on SIGTERM, SIGINT, SIGQUIT or an admin shutdown.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Time readiness reports failing while new connections are still
+    /// accepted, so load balancers stop routing here before the listener
+    /// closes.
+    pub pre_stop_delay_ms: u64,
+    /// Time in-flight requests get to complete once the listener is closed.
+    pub drain_timeout_ms: u64,
+    /// Time a process started by an upgrade (SIGUSR2) gets to report ready
+    /// before it is killed and the current process keeps serving.
+    pub upgrade_timeout_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            pre_stop_delay_ms: 0,
+            drain_timeout_ms: 10000,
+            upgrade_timeout_ms: 30000,
+        }
+    }
+}
+
+/// Polling of the standard gRPC health service of every worker endpoint.
+///
+/// Endpoints reporting not-serving, such as a worker shutting down, get no
+/// new requests until they report serving again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    pub enabled: bool,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            enabled: false,
+            interval_ms: 1000,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// How the executor spreads over CPU cores.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub mode: RuntimeMode,
+    /// Executors started by the `processes` and `thread_per_core` modes,
+    /// one per core when 0.
+    pub instances: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeMode {
+    /// One process with a multi-threaded runtime.
+    #[default]
+    Single,
+    /// Processes with a single-threaded runtime each, all accepting on the
+    /// same addresses through `SO_REUSEPORT`.
+    Processes,
+    /// Single-threaded runtimes on threads pinned to cores, each with its
+    /// own listeners, through `SO_REUSEPORT`, and worker channels.
+    ThreadPerCore,
+}
+
+impl Config {
+    /// Configured listeners, or a single TCP listener on `listen_addr`.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![ListenerConfig {
+            address: Some(self.listen_addr),
+            ..ListenerConfig::default()
+        }]
+    }
+
+    /// Loads the configuration from the path given as first argument or in
+    /// `MS_EXECUTOR_CONFIG`, falling back to the defaults when neither is set.
+    pub fn load() -> Result<Config, String> {
+        let path = std::env::args()
+            .nth(1)
+            .or_else(|| std::env::var(CONFIG_ENV).ok());
+
+        match path {
+            Some(path) => Config::from_file(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+}
+
+pub fn contains_addr(networks: &[IpNet], addr: IpAddr) -> bool {
+    let addr = addr.to_canonical();
+    networks.iter().any(|net| net.contains(&addr))
+}
+
+// Accepts both plain addresses ("10.0.0.1") and networks ("10.0.0.0/8").
+fn deserialize_networks<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| serde::de::Error::custom(format!("invalid address: {}", entry)))
+        })
+        .collect()
+}
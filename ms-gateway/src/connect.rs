@@ -0,0 +1,246 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::http::uri::Authority;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+
+use crate::body::{self, ResponseBody};
+use crate::config::ConnectConfig;
+use crate::connection::{ConnectionSlot, ConnectionStats};
+use crate::cors::wildcard_match;
+use crate::error_response;
+
+/// Checks a CONNECT request against the configuration and returns its
+/// destination, or the status refusing it.
+pub fn authorize<B>(config: &ConnectConfig, request: &Request<B>) -> Result<Authority, StatusCode> {
+    if !config.enabled {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    if !config.users.is_empty() && !authenticated(config, request.headers()) {
+        return Err(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+    }
+
+    let Some(authority) = request.uri().authority().filter(|a| a.port().is_some()) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if !is_allowed(config, authority) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(authority.clone())
+}
+
+/// Response refusing a CONNECT request with `status`.
+pub fn refused(status: StatusCode) -> Response<ResponseBody> {
+    let mut res = error_response(status);
+    if status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+        res.headers_mut().insert(
+            header::PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"ms-executor\""),
+        );
+    }
+    res
+}
+
+/// Connects to `destination` and, once the client connection is handed
+/// over, copies bytes both ways in a task counted as a request in flight.
+pub async fn tunnel(
+    config: &ConnectConfig,
+    destination: Authority,
+    on_upgrade: OnUpgrade,
+    connections: &Arc<ConnectionStats>,
+    slot: Option<Arc<ConnectionSlot>>,
+) -> Response<ResponseBody> {
+    let timeout = Duration::from_millis(config.connect_timeout_ms);
+    let connect = TcpStream::connect(destination.as_str());
+    let mut upstream = match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            eprintln!("CONNECT {} error: {}", destination, e);
+            return error_response(StatusCode::BAD_GATEWAY);
+        }
+        Err(_) => {
+            eprintln!(
+                "CONNECT {} error: no connection after {:?}",
+                destination, timeout
+            );
+            return error_response(StatusCode::GATEWAY_TIMEOUT);
+        }
+    };
+
+    let request_guard = connections.start_request();
+    tokio::spawn(async move {
+        let _request_guard = request_guard;
+        let _slot = slot;
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let mut client = TokioIo::new(upgraded);
+                if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    eprintln!("CONNECT {} tunnel error: {}", destination, e);
+                }
+            }
+            Err(e) => eprintln!("upgrade error: {}", e),
+        }
+    });
+
+    Response::new(body::full(""))
+}
+
+fn authenticated(config: &ConnectConfig, headers: &HeaderMap) -> bool {
+    let Some(credentials) = headers
+        .get(header::PROXY_AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| {
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .ok()
+        })
+    else {
+        return false;
+    };
+
+    config.users.iter().any(|user| {
+        let expected = format!("{}:{}", user.username, user.password);
+        constant_time_eq(expected.as_bytes(), &credentials)
+    })
+}
+
+// Compares without returning early, so the time taken does not reveal how
+// much of a password matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_allowed(config: &ConnectConfig, destination: &Authority) -> bool {
+    let host = destination.host().to_ascii_lowercase();
+    let port = destination.port_u16().unwrap_or_default().to_string();
+    config.allowed_destinations.iter().any(|allowed| {
+        let Some((allowed_host, allowed_port)) = allowed.rsplit_once(':') else {
+            return false;
+        };
+        wildcard_match(&allowed_host.to_ascii_lowercase(), &host)
+            && wildcard_match(allowed_port, &port)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyUserConfig;
+
+    fn config() -> ConnectConfig {
+        ConnectConfig {
+            enabled: true,
+            allowed_destinations: ["*.example.com:443", "db.internal:5432", "10.0.0.1:*"]
+                .map(String::from)
+                .to_vec(),
+            users: vec![ProxyUserConfig {
+                username: "alice".to_string(),
+                password: "s3cret".to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn connect(destination: &str, credentials: Option<&str>) -> Request<()> {
+        let mut request = Request::connect(destination);
+        if let Some(credentials) = credentials {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+            request = request.header(header::PROXY_AUTHORIZATION, format!("Basic {}", encoded));
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn allowed_destinations() {
+        let config = config();
+        let allowed = |destination: &str| is_allowed(&config, &destination.parse().unwrap());
+
+        assert!(allowed("api.example.com:443"));
+        assert!(allowed("API.Example.COM:443"));
+        assert!(!allowed("api.example.com:80"));
+        assert!(!allowed("example.com:443"));
+        assert!(!allowed("example.com.evil.org:443"));
+        assert!(allowed("db.internal:5432"));
+        assert!(!allowed("db.internal:54321"));
+        assert!(allowed("10.0.0.1:22"));
+        assert!(!allowed("10.0.0.10:22"));
+        assert!(!is_allowed(
+            &ConnectConfig::default(),
+            &"a.example.com:443".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn basic_authentication() {
+        let config = config();
+        let authenticated =
+            |credentials| authenticated(&config, connect("a:1", credentials).headers());
+
+        assert!(authenticated(Some("alice:s3cret")));
+        assert!(!authenticated(Some("alice:s3cre")));
+        assert!(!authenticated(Some("alice:s3cret2")));
+        assert!(!authenticated(Some("bob:s3cret")));
+        assert!(!authenticated(None));
+
+        let bearer = Request::connect("a:1")
+            .header(header::PROXY_AUTHORIZATION, "Bearer alice:s3cret")
+            .body(())
+            .unwrap();
+        assert!(!super::authenticated(&config, bearer.headers()));
+        let invalid = Request::connect("a:1")
+            .header(header::PROXY_AUTHORIZATION, "Basic !!!")
+            .body(())
+            .unwrap();
+        assert!(!super::authenticated(&config, invalid.headers()));
+    }
+
+    #[test]
+    fn authorization() {
+        let config = config();
+        let credentials = Some("alice:s3cret");
+
+        assert_eq!(
+            authorize(&config, &connect("api.example.com:443", credentials)).unwrap(),
+            "api.example.com:443"
+        );
+        assert_eq!(
+            authorize(&config, &connect("api.example.com:443", None)),
+            Err(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        );
+        assert_eq!(
+            authorize(&config, &connect("api.example.com", credentials)),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            authorize(&config, &connect("evil.org:443", credentials)),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            authorize(
+                &ConnectConfig::default(),
+                &connect("api.example.com:443", None)
+            ),
+            Err(StatusCode::METHOD_NOT_ALLOWED)
+        );
+
+        let open = ConnectConfig {
+            users: Vec::new(),
+            ..config
+        };
+        assert!(authorize(&open, &connect("api.example.com:443", None)).is_ok());
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::{Notify, OwnedSemaphorePermit};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Address of either end of a client connection.
+#[derive(Debug, Clone)]
+pub enum Address {
+    Inet(SocketAddr),
+    /// Path of a Unix domain socket, unnamed sockets have none.
+    Unix(Option<PathBuf>),
+}
+
+impl Address {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Address::Inet(addr) => Some(addr.ip()),
+            Address::Unix(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Inet(addr) => write!(f, "{}", addr),
+            Address::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Address::Unix(None) => write!(f, "unix:"),
+        }
+    }
+}
+
+/// Parameters negotiated by the TLS handshake of a connection.
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    pub protocol: String,
+    pub cipher: String,
+    /// Server name the client asked for, empty without SNI.
+    pub sni: String,
+}
+
+/// Metadata of an accepted client connection, forwarded to the workers with
+/// every request made on it.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer_addr: Address,
+    pub local_addr: Address,
+    pub scheme: &'static str,
+    pub tls: Option<TlsInfo>,
+    /// Path prefixes served on the listener, all paths when empty.
+    pub routes: Arc<[String]>,
+}
+
+impl ConnectionInfo {
+    pub fn new(
+        peer_addr: Address,
+        local_addr: Address,
+        tls: Option<TlsInfo>,
+        routes: Arc<[String]>,
+    ) -> ConnectionInfo {
+        ConnectionInfo {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            local_addr,
+            scheme: if tls.is_some() { "https" } else { "http" },
+            tls,
+            routes,
+        }
+    }
+
+    /// Whether the listener serves `path`.
+    pub fn routes_path(&self, path: &str) -> bool {
+        self.routes.is_empty()
+            || self
+                .routes
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// Counts of client connections and requests, as reported by the admin API.
+#[derive(Debug, Default, Serialize)]
+pub struct ConnectionStats {
+    pub active: AtomicUsize,
+    pub total: AtomicU64,
+    /// Requests received whose response has not been produced yet.
+    pub in_flight_requests: AtomicUsize,
+    /// Connections closed on accept because of `max_connections`.
+    pub rejected: AtomicU64,
+    /// Connections closed for sending or reading too slowly.
+    pub slow_clients: AtomicU64,
+    #[serde(skip)]
+    closed: Notify,
+}
+
+impl ConnectionStats {
+    /// Records an accepted connection until the returned guard is dropped.
+    pub fn open(self: &Arc<Self>) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Completes once no connection is open.
+    pub async fn all_closed(&self) {
+        loop {
+            let mut notified = pin!(self.closed.notified());
+            notified.as_mut().enable();
+            if self.active.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Records a request in flight until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> RequestGuard {
+        self.in_flight_requests.fetch_add(1, Ordering::Relaxed);
+        RequestGuard(self.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard(Arc<ConnectionStats>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        self.0.closed.notify_waiters();
+    }
+}
+
+/// What a client connection holds while open: its count in
+/// [`ConnectionStats`] and its `max_connections` permit. Tunnels keep them
+/// once the connection is upgraded, until they close.
+#[derive(Debug)]
+pub(crate) struct ConnectionSlot {
+    _guard: ConnectionGuard,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionSlot {
+    pub(crate) fn new(
+        guard: ConnectionGuard,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Arc<ConnectionSlot> {
+        Arc::new(ConnectionSlot {
+            _guard: guard,
+            _permit: permit,
+        })
+    }
+}
+
+pub struct RequestGuard(Arc<ConnectionStats>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.in_flight_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,101 @@
+//! Conversions between HTTP messages and their `httpgrpc` representation.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request::Parts;
+use hyper::{StatusCode, Version};
+use uuid::Uuid;
+
+// httpgrpc - protos
+use protos::httpgrpc::{Header, HttpRequest};
+
+use crate::connection::ConnectionInfo;
+
+/// Builds the request sent to a worker from the parts of an HTTP request,
+/// its collected body and trailers, and the connection it came on.
+pub fn to_grpc_request(
+    parts: &Parts,
+    body: Vec<u8>,
+    trailers: Vec<Header>,
+    conn_info: &ConnectionInfo,
+) -> HttpRequest {
+    let tls = conn_info.tls.clone().unwrap_or_default();
+    HttpRequest {
+        id: Uuid::new_v4().to_string(),
+        version: to_grpc_version(parts.version).to_string(),
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        body,
+        headers: to_grpc_headers(&parts.headers),
+        peer_addr: conn_info.peer_addr.to_string(),
+        local_addr: conn_info.local_addr.to_string(),
+        scheme: conn_info.scheme.to_string(),
+        tls_protocol: tls.protocol,
+        tls_cipher: tls.cipher,
+        tls_sni: tls.sni,
+        connection_id: conn_info.id,
+        trailers,
+    }
+}
+
+/// Version of a request as sent to the workers, such as "HTTP/1.1".
+pub fn to_grpc_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2.0",
+        Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+}
+
+/// Version of a worker response, HTTP/1.1 unless it names another one.
+pub fn to_http_version(version: &str) -> Version {
+    match version {
+        "HTTP_09" => Version::HTTP_09,
+        "HTTP_10" => Version::HTTP_10,
+        "HTTP_11" => Version::HTTP_11,
+        "HTTP_2" => Version::HTTP_2,
+        "HTTP_3" => Version::HTTP_3,
+        _ => Version::HTTP_11,
+    }
+}
+
+/// Status of a worker response, 500 when it is not a valid status code.
+pub fn to_http_status(status: i32) -> StatusCode {
+    u16::try_from(status)
+        .ok()
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Converts HTTP headers into their gRPC representation, one `Header` per
+/// name with all of its values.
+pub fn to_grpc_headers(headers: &HeaderMap) -> Vec<Header> {
+    headers
+        .keys()
+        .map(|key| Header {
+            key: key.to_string(),
+            values: headers
+                .get_all(key)
+                .iter()
+                .map(|value| value.to_str().unwrap_or_default().to_string())
+                .collect(),
+        })
+        .collect()
+}
+
+/// Converts gRPC headers into HTTP headers, skipping invalid names and values.
+pub fn to_http_headers(headers: Vec<Header>) -> HeaderMap {
+    let mut headers_map = HeaderMap::new();
+    for header in headers {
+        if let Ok(key) = <HeaderName as std::str::FromStr>::from_str(&header.key) {
+            for string_value in header.values {
+                if let Ok(value) = HeaderValue::from_str(&string_value) {
+                    headers_map.append(&key, value);
+                }
+            }
+        }
+    }
+    headers_map
+}
@@ -0,0 +1,466 @@
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, Request, StatusCode};
+
+use crate::config::CorsConfig;
+
+/// Returns the first policy whose hosts and path prefix match the request.
+pub fn find_policy<'a>(
+    policies: &'a [CorsConfig],
+    host: Option<&str>,
+    path: &str,
+) -> Option<&'a CorsConfig> {
+    // The Host header may carry a port, policies name hosts only
+    let host = host.map(|host| match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    });
+
+    policies.iter().find(|policy| {
+        let host_matches = policy.hosts.is_empty()
+            || host.is_some_and(|host| {
+                policy.hosts.iter().any(|pattern| {
+                    wildcard_match(&pattern.to_ascii_lowercase(), &host.to_ascii_lowercase())
+                })
+            });
+        host_matches && path.starts_with(&policy.path_prefix)
+    })
+}
+
+pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::OPTIONS
+        && headers.contains_key(header::ORIGIN)
+        && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers a preflight request without involving a worker.
+///
+/// Disallowed origins, methods or headers get a 403 without CORS headers,
+/// which the browser reports as a failed preflight.
+pub fn preflight(policy: &CorsConfig, request_headers: &HeaderMap) -> (StatusCode, HeaderMap) {
+    let mut headers = HeaderMap::new();
+    add_vary(policy, &mut headers, true);
+
+    let Some(origin) = allowed_origin(policy, request_headers) else {
+        return (StatusCode::FORBIDDEN, headers);
+    };
+
+    let method = request_headers
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let method_allowed = policy
+        .allowed_methods
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(method));
+    if !method_allowed {
+        return (StatusCode::FORBIDDEN, headers);
+    }
+
+    let requested_headers: Vec<String> = request_headers
+        .get_all(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let any_header = policy.allowed_headers.iter().any(|allowed| allowed == "*");
+    let headers_allowed = any_header
+        || requested_headers.iter().all(|name| {
+            policy
+                .allowed_headers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+        });
+    if !headers_allowed {
+        return (StatusCode::FORBIDDEN, headers);
+    }
+
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    if policy.allow_credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if let Ok(value) = HeaderValue::from_str(&policy.allowed_methods.join(", ")) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, value);
+    }
+    // A literal "*" is not a wildcard for credentialed requests, so the
+    // requested names are echoed instead.
+    let allow_headers = if any_header {
+        requested_headers.join(", ")
+    } else {
+        policy.allowed_headers.join(", ")
+    };
+    if !allow_headers.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&allow_headers) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+        }
+    }
+    if let Some(max_age) = policy.max_age {
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+    }
+
+    (StatusCode::NO_CONTENT, headers)
+}
+
+/// Adds the CORS headers of an actual (non preflight) request to the
+/// worker's response.
+pub fn add_response_headers(
+    policy: &CorsConfig,
+    request_headers: &HeaderMap,
+    response_headers: &mut HeaderMap,
+) {
+    add_vary(policy, response_headers, false);
+
+    let Some(origin) = allowed_origin(policy, request_headers) else {
+        return;
+    };
+    response_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    if policy.allow_credentials {
+        response_headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if !policy.exposed_headers.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&policy.exposed_headers.join(", ")) {
+            response_headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, value);
+        }
+    }
+}
+
+/// What the CORS headers of a response depend on, taken from the request
+/// before it goes through the service.
+#[derive(Debug)]
+pub struct CorsRequest {
+    host: Option<String>,
+    path: String,
+    // Origin only
+    headers: HeaderMap,
+    preflight: bool,
+}
+
+impl CorsRequest {
+    pub fn new<B>(request: &Request<B>) -> CorsRequest {
+        let host = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request.uri().authority().map(|a| a.as_str()))
+            .map(|host| host.to_string());
+        let mut headers = HeaderMap::new();
+        if let Some(origin) = request.headers().get(header::ORIGIN) {
+            headers.insert(header::ORIGIN, origin.clone());
+        }
+        CorsRequest {
+            host,
+            path: request.uri().path().to_string(),
+            headers,
+            preflight: is_preflight(request.method(), request.headers()),
+        }
+    }
+
+    /// Adds the headers of the matching policy to any response, errors
+    /// included, so browsers report the actual status. Preflights are
+    /// answered with headers of their own.
+    pub fn add_response_headers(&self, policies: &[CorsConfig], response_headers: &mut HeaderMap) {
+        if self.preflight {
+            return;
+        }
+        if let Some(policy) = find_policy(policies, self.host.as_deref(), &self.path) {
+            add_response_headers(policy, &self.headers, response_headers);
+        }
+    }
+}
+
+// Value of Access-Control-Allow-Origin for the request, None when the
+// origin is not allowed.
+fn allowed_origin(policy: &CorsConfig, request_headers: &HeaderMap) -> Option<HeaderValue> {
+    let origin = request_headers.get(header::ORIGIN)?;
+    let origin_str = origin.to_str().ok()?;
+
+    if policy.allowed_origins.iter().any(|allowed| allowed == "*") {
+        if policy.allow_credentials {
+            return Some(origin.clone());
+        }
+        return Some(HeaderValue::from_static("*"));
+    }
+
+    policy
+        .allowed_origins
+        .iter()
+        .any(|pattern| {
+            wildcard_match(
+                &pattern.to_ascii_lowercase(),
+                &origin_str.to_ascii_lowercase(),
+            )
+        })
+        .then(|| origin.clone())
+}
+
+// Responses depend on Origin unless every origin gets the same "*".
+fn add_vary(policy: &CorsConfig, headers: &mut HeaderMap, preflight: bool) {
+    let origin_independent =
+        !policy.allow_credentials && policy.allowed_origins.iter().any(|allowed| allowed == "*");
+    if !origin_independent {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    if preflight {
+        headers.append(
+            header::VARY,
+            HeaderValue::from_static("Access-Control-Request-Method"),
+        );
+        headers.append(
+            header::VARY,
+            HeaderValue::from_static("Access-Control-Request-Headers"),
+        );
+    }
+}
+
+/// Glob match where '*' stands for any sequence of characters, as in
+/// "https://*.example.com".
+pub fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !value.starts_with(first) || !value[first.len()..].ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn policy() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: strings(&["https://*.example.com"]),
+            allowed_methods: strings(&["GET", "PUT"]),
+            allowed_headers: strings(&["Content-Type"]),
+            max_age: Some(600),
+            ..Default::default()
+        }
+    }
+
+    fn preflight_request(origin: &str, method: &str, request_headers: &str) -> HeaderMap {
+        let mut pairs = vec![
+            ("origin", origin),
+            ("access-control-request-method", method),
+        ];
+        if !request_headers.is_empty() {
+            pairs.push(("access-control-request-headers", request_headers));
+        }
+        headers(&pairs)
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(wildcard_match("example.com", "example.com"));
+        assert!(!wildcard_match("example.com", "www.example.com"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*.example.com", "api.example.com"));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com.evil.org"));
+        assert!(wildcard_match(
+            "https://*.example.*",
+            "https://api.example.org"
+        ));
+        assert!(wildcard_match("a*b*c", "abc"));
+        assert!(wildcard_match("a*b*c", "a-b-b-c"));
+        assert!(!wildcard_match("a*b*c", "acb"));
+        // The prefix and suffix must not overlap
+        assert!(!wildcard_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn policy_matching() {
+        let policies = [
+            CorsConfig {
+                hosts: strings(&["*.example.com"]),
+                path_prefix: "/api/".to_string(),
+                max_age: Some(1),
+                ..Default::default()
+            },
+            CorsConfig {
+                max_age: Some(2),
+                ..Default::default()
+            },
+        ];
+        let found = |host, path| find_policy(&policies, host, path).and_then(|p| p.max_age);
+
+        assert_eq!(found(Some("api.example.com"), "/api/users"), Some(1));
+        assert_eq!(found(Some("API.Example.com:8443"), "/api/users"), Some(1));
+        assert_eq!(found(Some("api.example.com"), "/static/app.js"), Some(2));
+        assert_eq!(found(Some("example.org"), "/api/users"), Some(2));
+        assert_eq!(found(None, "/api/users"), Some(2));
+        assert!(find_policy(&policies[..1], None, "/api/users").is_none());
+        assert!(find_policy(&[], Some("example.com"), "/").is_none());
+    }
+
+    #[test]
+    fn preflight_detection() {
+        let request = preflight_request("https://a.example.com", "PUT", "");
+        assert!(is_preflight(&Method::OPTIONS, &request));
+        assert!(!is_preflight(&Method::GET, &request));
+        let without_method = headers(&[("origin", "https://a.example.com")]);
+        assert!(!is_preflight(&Method::OPTIONS, &without_method));
+    }
+
+    #[test]
+    fn allowed_preflight() {
+        let request = preflight_request("https://a.example.com", "put", "content-type");
+        let (status, headers) = preflight(&policy(), &request);
+
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://a.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        let vary: Vec<_> = headers.get_all(header::VARY).iter().collect();
+        assert_eq!(
+            vary,
+            [
+                "Origin",
+                "Access-Control-Request-Method",
+                "Access-Control-Request-Headers"
+            ]
+        );
+    }
+
+    #[test]
+    fn refused_preflights() {
+        for request in [
+            preflight_request("https://example.org", "GET", ""),
+            preflight_request("https://a.example.com", "DELETE", ""),
+            preflight_request("https://a.example.com", "GET", "content-type, x-token"),
+        ] {
+            let (status, headers) = preflight(&policy(), &request);
+            assert_eq!(status, StatusCode::FORBIDDEN, "{:?}", request);
+            assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+    }
+
+    #[test]
+    fn wildcard_preflight() {
+        let any = CorsConfig {
+            allowed_origins: strings(&["*"]),
+            allowed_methods: strings(&["*"]),
+            allowed_headers: strings(&["*"]),
+            ..Default::default()
+        };
+        let request = preflight_request("https://example.org", "PATCH", "x-a, X-B");
+        let (status, headers) = preflight(&any, &request);
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        // "*" is echoed as the requested names
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "x-a, x-b");
+        assert!(!headers.get_all(header::VARY).iter().any(|v| v == "Origin"));
+
+        // Credentialed requests get the origin itself
+        let credentialed = CorsConfig {
+            allow_credentials: true,
+            ..any
+        };
+        let (_, headers) = preflight(&credentialed, &request);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.org"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert!(headers.get_all(header::VARY).iter().any(|v| v == "Origin"));
+    }
+
+    #[test]
+    fn actual_request_headers() {
+        let policy = CorsConfig {
+            exposed_headers: strings(&["X-Request-Id", "X-Total"]),
+            ..policy()
+        };
+
+        let mut response = HeaderMap::new();
+        add_response_headers(
+            &policy,
+            &headers(&[("origin", "https://a.example.com")]),
+            &mut response,
+        );
+        assert_eq!(
+            response[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://a.example.com"
+        );
+        assert_eq!(
+            response[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "X-Request-Id, X-Total"
+        );
+        assert_eq!(response[header::VARY], "Origin");
+
+        let mut response = HeaderMap::new();
+        add_response_headers(
+            &policy,
+            &headers(&[("origin", "https://example.org")]),
+            &mut response,
+        );
+        assert!(!response.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(response[header::VARY], "Origin");
+    }
+
+    #[test]
+    fn cors_request_skips_preflights() {
+        let policies = [policy()];
+        let request = |method| {
+            Request::builder()
+                .method(method)
+                .uri("/users")
+                .header(header::HOST, "api.example.com")
+                .header(header::ORIGIN, "https://a.example.com")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+                .body(())
+                .unwrap()
+        };
+
+        let mut response = HeaderMap::new();
+        CorsRequest::new(&request(Method::GET)).add_response_headers(&policies, &mut response);
+        assert!(response.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let mut response = HeaderMap::new();
+        CorsRequest::new(&request(Method::OPTIONS)).add_response_headers(&policies, &mut response);
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use hyper::body::Incoming;
+use hyper::{Request, Response};
+use tower::{BoxError, Layer, Service};
+
+use crate::body::ResponseBody;
+use crate::cache::ResponseCache;
+use crate::coalesce::Coalescer;
+use crate::config::{Config, ListenerConfig, WorkerEndpointConfig};
+use crate::connection::ConnectionStats;
+use crate::handoff::InheritedAddr;
+use crate::listener::Listener;
+use crate::middleware::{self, GatewayService, LayerFn, Middleware, MiddlewareLayer};
+use crate::pool::WorkerPool;
+use crate::runtime::{Instance, Readiness};
+use crate::AppState;
+
+/// Builds a [`Gateway`] from a configuration, with listeners, workers and
+/// middleware added on top of it.
+pub struct GatewayBuilder {
+    config: Config,
+    pool: Option<WorkerPool>,
+    layers: Vec<LayerFn>,
+    instance: Option<Instance>,
+    handle_signals: bool,
+}
+
+impl GatewayBuilder {
+    /// Adds a listener. As with the `listeners` setting, `listen_addr` is
+    /// then no longer bound.
+    pub fn listener(mut self, listener: ListenerConfig) -> Self {
+        self.config.listeners.push(listener);
+        self
+    }
+
+    /// Adds a worker endpoint to the ones of the configuration.
+    pub fn worker_endpoint(mut self, uri: impl Into<String>, weight: u32) -> Self {
+        self.config
+            .worker_endpoints
+            .push(WorkerEndpointConfig::Weighted {
+                uri: uri.into(),
+                weight,
+            });
+        self
+    }
+
+    /// Forwards to `pool` instead of a pool of the configured
+    /// `worker_endpoints`.
+    pub fn worker_pool(mut self, pool: WorkerPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Adds a tower layer around the request path. Layers added first see
+    /// requests first, and all of them wrap the layers of `[middleware]`.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<GatewayService> + Send + 'static,
+        L::Service:
+            Service<Request<Incoming>, Response = Response<ResponseBody>> + Clone + Send + 'static,
+        <L::Service as Service<Request<Incoming>>>::Error: Into<BoxError>,
+        <L::Service as Service<Request<Incoming>>>::Future: Send + 'static,
+    {
+        self.layers.push(middleware::layer_fn(layer));
+        self
+    }
+
+    /// Adds a [`Middleware`], run as a layer in the order of [`layer`].
+    ///
+    /// [`layer`]: GatewayBuilder::layer
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        let layer = MiddlewareLayer(Arc::new(middleware));
+        self.layers.push(middleware::layer_fn(layer));
+        self
+    }
+
+    /// Runs as one of the instances started by [`runtime::start`], with
+    /// the sockets it inherited. A single instance without inherited
+    /// sockets otherwise.
+    ///
+    /// [`runtime::start`]: crate::runtime::start
+    pub fn instance(mut self, instance: Instance) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+
+    /// Whether the gateway shuts down on SIGTERM, SIGINT and SIGQUIT and
+    /// upgrades on SIGUSR2, true by default. Applications handling signals
+    /// themselves use a [`ShutdownHandle`] instead.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    /// Connects the worker pool and binds the listeners. Must be called
+    /// from a tokio runtime.
+    pub fn build(self) -> Result<Gateway, String> {
+        let GatewayBuilder {
+            config,
+            pool,
+            layers,
+            instance,
+            handle_signals,
+        } = self;
+
+        let pool = match pool {
+            Some(pool) => pool,
+            None => {
+                let pool = WorkerPool::new(config.grpc.clone());
+                for endpoint in &config.worker_endpoints {
+                    pool.add(endpoint.uri(), endpoint.weight())
+                        .map_err(|e| format!("config error: {}", e))?;
+                }
+                pool
+            }
+        };
+
+        let cache = if config.cache.enabled {
+            let cache = ResponseCache::new(config.cache.clone())
+                .map_err(|e| format!("cache error: {}", e))?;
+            Some(cache)
+        } else {
+            None
+        };
+
+        let coalescer = config
+            .coalescing
+            .enabled
+            .then(|| Coalescer::new(config.coalescing.clone()));
+
+        // Sockets from systemd or from the process this one replaces are
+        // used instead of binding, so no connection is refused meanwhile
+        let instance = instance.unwrap_or(Instance {
+            index: 0,
+            count: 1,
+            inherited: Vec::new(),
+            readiness: None,
+        });
+        // Each instance has its own state, so its own admin port
+        let admin_addr = if config.admin.enabled {
+            let mut admin_addr = config.admin.listen_addr;
+            let port = u16::try_from(instance.index)
+                .ok()
+                .and_then(|index| admin_addr.port().checked_add(index))
+                .ok_or_else(|| {
+                    format!(
+                        "config error: admin port {} + instance {} exceeds 65535",
+                        admin_addr.port(),
+                        instance.index
+                    )
+                })?;
+            admin_addr.set_port(port);
+            Some(admin_addr)
+        } else {
+            None
+        };
+        let shared = instance.is_shared();
+        let primary = instance.is_primary();
+        let readiness = instance.readiness;
+        let mut inherited = instance.inherited;
+        // Unix sockets cannot be shared, the first instance serves them
+        if !primary {
+            inherited.retain(|socket| !matches!(socket.addr, InheritedAddr::Unix(_)));
+        }
+        let mut listeners = Vec::new();
+        for listener_config in config.listeners() {
+            if listener_config.path.is_some() && !primary {
+                continue;
+            }
+            let listener = Listener::bind(&listener_config, &mut inherited, shared)
+                .map_err(|e| format!("listener error: {}", e))?;
+            println!("Listening on {}", listener.name);
+            listeners.push(Arc::new(listener));
+        }
+        for socket in inherited {
+            match Listener::from_inherited(socket) {
+                Ok(listener) => {
+                    println!("Listening on inherited {}", listener.name);
+                    listeners.push(Arc::new(listener));
+                }
+                Err(e) => eprintln!("inherited listener error: {}", e),
+            }
+        }
+
+        let state = Arc::new(AppState {
+            config,
+            pool,
+            cache,
+            coalescer,
+            maintenance: AtomicBool::new(false),
+            connections: Arc::new(ConnectionStats::default()),
+            shutdown: tokio::sync::Notify::new(),
+            draining: AtomicBool::new(false),
+            upgrade: tokio::sync::Notify::new(),
+            upgrading: AtomicBool::new(false),
+        });
+        let service = middleware::stack(state.clone(), &state.config.middleware, layers);
+
+        Ok(Gateway {
+            state,
+            service,
+            listeners,
+            admin_addr,
+            shared,
+            readiness,
+            handle_signals,
+        })
+    }
+}
+
+/// HTTP gateway with its listeners bound, forwarding to gRPC workers once
+/// served.
+pub struct Gateway {
+    pub(crate) state: Arc<AppState>,
+    pub(crate) service: GatewayService,
+    pub(crate) listeners: Vec<Arc<Listener>>,
+    pub(crate) admin_addr: Option<SocketAddr>,
+    pub(crate) shared: bool,
+    pub(crate) readiness: Option<Arc<Readiness>>,
+    pub(crate) handle_signals: bool,
+}
+
+impl Gateway {
+    pub fn builder(config: Config) -> GatewayBuilder {
+        GatewayBuilder {
+            config,
+            pool: None,
+            layers: Vec::new(),
+            instance: None,
+            handle_signals: true,
+        }
+    }
+
+    /// Workers the gateway forwards to; endpoints can be added, drained
+    /// and removed while it serves.
+    pub fn pool(&self) -> &WorkerPool {
+        &self.state.pool
+    }
+
+    /// Counts of client connections and requests.
+    pub fn connections(&self) -> &Arc<ConnectionStats> {
+        &self.state.connections
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.state.clone())
+    }
+
+    /// Accepts connections until shutdown, then drains them as configured
+    /// in `shutdown`. Also serves the admin API and runs the worker health
+    /// checks when they are enabled.
+    pub async fn serve(self) -> Result<(), String> {
+        crate::serve(self).await
+    }
+}
+
+/// Starts the graceful shutdown of a [`Gateway`] from anywhere, as the
+/// termination signals do.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<AppState>);
+
+impl ShutdownHandle {
+    /// Fails readiness, closes the listeners after `pre_stop_delay_ms` and
+    /// drains the connections.
+    pub fn shutdown(&self) {
+        self.0.shutdown.notify_one();
+    }
+
+    /// Whether shutdown started.
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::Relaxed)
+    }
+}
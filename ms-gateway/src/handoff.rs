@@ -0,0 +1,226 @@
+// Listening sockets inherited from systemd socket activation or from a
+// previous executor process, and the re-exec upgrade handing them over.
+//
+// On SIGUSR2 (or `POST /upgrade` on the admin API) the executor starts a
+// new copy of itself with the listener fds left open, listed in
+// `MS_EXECUTOR_LISTEN_FDS`. The new process serves on them and writes to
+// the `MS_EXECUTOR_READY_FD` pipe once ready; the old one then drains and
+// exits. The listening sockets are never closed, so no connection is
+// refused during the restart.
+
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+// systemd's first passed fd (sd_listen_fds)
+const SD_LISTEN_FDS_START: RawFd = 3;
+const LISTEN_FDS_ENV: &str = "MS_EXECUTOR_LISTEN_FDS";
+const READY_FD_ENV: &str = "MS_EXECUTOR_READY_FD";
+
+/// A listening socket received from the parent process.
+#[derive(Debug)]
+pub struct InheritedSocket {
+    pub fd: OwnedFd,
+    pub addr: InheritedAddr,
+}
+
+impl InheritedSocket {
+    pub fn try_clone(&self) -> io::Result<InheritedSocket> {
+        Ok(InheritedSocket {
+            fd: self.fd.try_clone()?,
+            addr: self.addr.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InheritedAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Takes the listening sockets passed to this process, from systemd
+/// (`LISTEN_FDS`) or from a previous executor (`MS_EXECUTOR_LISTEN_FDS`).
+///
+/// The variables are removed so processes started later do not see them.
+pub fn inherited_sockets() -> Vec<InheritedSocket> {
+    let mut fds: Vec<RawFd> = Vec::new();
+
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    if let Some(count) = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+    {
+        if for_us {
+            fds.extend(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count);
+        }
+    }
+    if let Ok(list) = std::env::var(LISTEN_FDS_ENV) {
+        fds.extend(
+            list.split(',')
+                .filter_map(|fd| fd.trim().parse::<RawFd>().ok()),
+        );
+    }
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES", LISTEN_FDS_ENV] {
+        std::env::remove_var(name);
+    }
+
+    let mut sockets = Vec::new();
+    for fd in fds {
+        // SAFETY: the fd was passed to this process to be owned by it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        match listening_addr(&fd) {
+            Ok(addr) => sockets.push(InheritedSocket { fd, addr }),
+            Err(e) => eprintln!("ignoring inherited fd {}: {}", fd.as_raw_fd(), e),
+        }
+    }
+    sockets
+}
+
+/// Makes sockets from systemd available to child processes, which the
+/// `LISTEN_PID` check would otherwise exclude.
+///
+/// Changes the environment, so must run before other threads are started.
+pub fn pass_to_children() {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let Some(count) = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if for_us {
+        let fds: Vec<String> = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| fd.to_string())
+            .collect();
+        std::env::set_var(LISTEN_FDS_ENV, fds.join(","));
+    }
+}
+
+fn listening_addr(fd: &OwnedFd) -> io::Result<InheritedAddr> {
+    let socket = socket2::SockRef::from(fd);
+    let addr = socket.local_addr()?;
+    if let Some(addr) = addr.as_socket() {
+        return Ok(InheritedAddr::Inet(addr));
+    }
+    match addr.as_pathname() {
+        Some(path) => Ok(InheritedAddr::Unix(path.to_path_buf())),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not an inet or named unix socket",
+        )),
+    }
+}
+
+/// Write end of the pipe telling the process that started this one that
+/// the listeners are serving and it can drain.
+#[derive(Debug)]
+pub struct ReadyPipe(OwnedFd);
+
+/// Takes the ready pipe passed by a previous executor, if any.
+///
+/// As with [`inherited_sockets`], the variable is removed, so this must run
+/// before other threads are started.
+pub fn ready_pipe() -> Option<ReadyPipe> {
+    let fd = std::env::var(READY_FD_ENV)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok())?;
+    std::env::remove_var(READY_FD_ENV);
+
+    // SAFETY: the fd is the write end of the pipe created by the parent
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // Processes started later must not keep the pipe open
+    // SAFETY: fcntl on an owned fd
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        eprintln!("ready pipe error: {}", io::Error::last_os_error());
+    }
+    Some(ReadyPipe(fd))
+}
+
+impl ReadyPipe {
+    pub fn notify(self) {
+        let mut pipe = std::fs::File::from(self.0);
+        if let Err(e) = pipe.write_all(b"1") {
+            eprintln!("upgrade ready notification error: {}", e);
+        }
+    }
+}
+
+/// Starts a new executor process inheriting `listener_fds`, and waits until
+/// it reports ready.
+pub async fn spawn_successor(listener_fds: Vec<RawFd>, timeout: Duration) -> Result<u32, String> {
+    let (ready_read, ready_write) = pipe().map_err(|e| format!("cannot create pipe: {}", e))?;
+
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    if args.is_empty() {
+        return Err("no program name".to_string());
+    }
+    // argv[0] rather than /proc/self/exe, which names the replaced binary
+    let mut command = Command::new(args.remove(0));
+    command.args(args);
+
+    let fd_list: Vec<String> = listener_fds.iter().map(|fd| fd.to_string()).collect();
+    command.env(LISTEN_FDS_ENV, fd_list.join(","));
+    command.env(READY_FD_ENV, ready_write.as_raw_fd().to_string());
+
+    let mut inherited = listener_fds;
+    inherited.push(ready_write.as_raw_fd());
+    // SAFETY: only fcntl, which is async-signal-safe, runs in the child
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &inherited {
+                if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("cannot start the new process: {}", e))?;
+    // Only the child holds the write end now, EOF means it exited
+    drop(ready_write);
+
+    let wait = tokio::task::spawn_blocking(move || {
+        let mut byte = [0u8; 1];
+        std::fs::File::from(ready_read).read(&mut byte)
+    });
+    let error = match tokio::time::timeout(timeout, wait).await {
+        Ok(Ok(Ok(1))) => return Ok(child.id()),
+        Ok(Ok(Ok(_))) => "exited before being ready".to_string(),
+        Ok(Ok(Err(e))) => format!("ready notification error: {}", e),
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => format!("not ready after {:?}", timeout),
+    };
+
+    // The old process keeps serving, a half started successor must not
+    let _ = child.kill();
+    let _ = child.wait();
+    Err(format!("new process {}: {}", child.id(), error))
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0 as RawFd; 2];
+    // SAFETY: pipe2 fills both fds on success
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both fds were just created and are owned here
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
@@ -0,0 +1,850 @@
+//! HTTP gateway forwarding requests to gRPC workers over the `httpgrpc`
+//! protocol, as run by `ms-executor`.
+//!
+//! A [`Gateway`] is built from a [`Config`], usually loaded
+//! from TOML, with listeners, workers and tower layers added through
+//! [`GatewayBuilder`]:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), String> {
+//! use ms_gateway::config::Config;
+//! use ms_gateway::Gateway;
+//!
+//! let gateway = Gateway::builder(Config::default())
+//!     .worker_endpoint("http://[::1]:50051", 1)
+//!     .layer(tower::limit::ConcurrencyLimitLayer::new(64))
+//!     .build()?;
+//! let shutdown = gateway.shutdown_handle();
+//! tokio::spawn(async move {
+//!     tokio::signal::ctrl_c().await.ok();
+//!     shutdown.shutdown();
+//! });
+//! gateway.serve().await
+//! # }
+//! ```
+
+mod admin;
+pub mod body;
+mod cache;
+mod coalesce;
+mod compression;
+pub mod config;
+mod connect;
+pub mod connection;
+pub mod convert;
+mod cors;
+mod gateway;
+mod handoff;
+mod limits;
+mod listener;
+pub mod middleware;
+pub mod pool;
+mod proxy_headers;
+mod proxy_protocol;
+pub mod runtime;
+mod shutdown;
+mod slow_client;
+mod sse;
+mod upgrade;
+mod websocket;
+mod worker_client;
+
+pub use gateway::{Gateway, GatewayBuilder, ShutdownHandle};
+pub use middleware::{GatewayService, Middleware};
+
+use hyper_util::rt::TokioTimer;
+
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// HTTP server - Hyper.rs
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::header::HeaderValue;
+use hyper::http::Version;
+use hyper::service::Service;
+use hyper::{Request, Response};
+
+// httpgrpc - protos
+use protos::httpgrpc::{HttpRequest, HttpResponse};
+
+use body::ResponseBody;
+use cache::{CacheStatus, ResponseCache};
+use coalesce::Coalescer;
+use config::{Config, OverLimit};
+use connection::{Address, ConnectionInfo, ConnectionSlot, ConnectionStats};
+use convert::{to_grpc_headers, to_grpc_request, to_http_headers, to_http_status, to_http_version};
+use cors::CorsRequest;
+use limits::ConnectionActivity;
+use listener::Listener;
+use pool::WorkerPool;
+use shutdown::{Signals, Termination};
+use slow_client::{BodyError, SlowClientIo};
+use sse::Streamed;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tower::{BoxError, ServiceExt};
+
+/// State shared by every connection of the executor.
+#[derive(Debug)]
+struct AppState {
+    config: Config,
+    pool: WorkerPool,
+    cache: Option<ResponseCache>,
+    coalescer: Option<Coalescer>,
+    /// Set through the admin API; requests are refused with 503 meanwhile.
+    maintenance: AtomicBool,
+    connections: Arc<ConnectionStats>,
+    /// Notified by the admin API to start a graceful shutdown.
+    shutdown: tokio::sync::Notify,
+    /// Set once shutdown starts; readiness fails from then on.
+    draining: AtomicBool,
+    /// Notified by the admin API to hand the listeners to a new process.
+    upgrade: tokio::sync::Notify,
+    upgrading: AtomicBool,
+}
+
+impl AppState {
+    /// Whether load balancers should send new traffic here.
+    fn is_ready(&self) -> bool {
+        !self.draining.load(Ordering::Relaxed) && !self.maintenance.load(Ordering::Relaxed)
+    }
+}
+
+async fn handle_request(
+    mut http_request: Request<Incoming>,
+    state: Arc<AppState>,
+    conn_info: Arc<ConnectionInfo>,
+) -> Result<Response<ResponseBody>, hyper::Error> {
+    let config = &state.config;
+    // Tunnels keep the connection counted and its permit until they close
+    let slot = http_request
+        .extensions()
+        .get::<Arc<ConnectionSlot>>()
+        .cloned();
+
+    if state.maintenance.load(Ordering::Relaxed) {
+        let mut res = error_response(hyper::StatusCode::SERVICE_UNAVAILABLE);
+        res.headers_mut()
+            .insert(hyper::header::RETRY_AFTER, HeaderValue::from_static("30"));
+        return Ok(res);
+    }
+
+    // CONNECT requests are tunneled, their authority-form URI has no path
+    let connect = http_request.method() == hyper::Method::CONNECT;
+    if connect {
+        let destination = match connect::authorize(&config.connect, &http_request) {
+            Ok(destination) => destination,
+            Err(status) => return Ok(connect::refused(status)),
+        };
+        if !config.connect.via_worker {
+            let on_upgrade = hyper::upgrade::on(&mut http_request);
+            let res = connect::tunnel(
+                &config.connect,
+                destination,
+                on_upgrade,
+                &state.connections,
+                slot,
+            )
+            .await;
+            return Ok(res);
+        }
+    } else if !conn_info.routes_path(http_request.uri().path()) {
+        return Ok(error_response(hyper::StatusCode::NOT_FOUND));
+    }
+
+    // Connection and Upgrade are hop-by-hop, read them before they go
+    let upgrade_protocol = upgrade::requested_protocol(http_request.headers())
+        .filter(|_| http_request.version() == Version::HTTP_11 && !connect);
+    let on_upgrade =
+        (upgrade_protocol.is_some() || connect).then(|| hyper::upgrade::on(&mut http_request));
+
+    // Rewrite proxy headers before they reach the worker
+    let http_host = http_request
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| http_request.uri().authority().map(|a| a.as_str()))
+        .map(|host| host.to_string());
+    let headers = http_request.headers_mut();
+    proxy_headers::strip_hop_by_hop(headers);
+    proxy_headers::add_forwarded(
+        headers,
+        &conn_info.peer_addr,
+        conn_info.scheme,
+        http_host.as_deref(),
+        &config.forwarded,
+    );
+    if let Some(protocol) = &upgrade_protocol {
+        headers.insert(
+            hyper::header::CONNECTION,
+            HeaderValue::from_static("upgrade"),
+        );
+        headers.insert(hyper::header::UPGRADE, protocol.clone());
+    }
+
+    // CORS preflights are answered here, without calling a worker
+    let cors_policy = cors::find_policy(
+        &config.cors,
+        http_host.as_deref(),
+        http_request.uri().path(),
+    );
+    if let Some(policy) = cors_policy {
+        if cors::is_preflight(http_request.method(), http_request.headers()) {
+            let (status, headers) = cors::preflight(policy, http_request.headers());
+            let mut res = Response::new(body::full(""));
+            *res.status_mut() = status;
+            *res.headers_mut() = headers;
+            return Ok(res);
+        }
+    }
+
+    let compression_encoding = if config.compression.enabled {
+        compression::negotiate(http_request.headers(), &config.compression.encodings)
+    } else {
+        None
+    };
+    let http_method_ref = http_request.method().clone();
+
+    // Create grpc request from http request
+    let (mut http_parts, http_incoming) = http_request.into_parts();
+
+    let mut request_body = slow_client::RequestBody::new(http_incoming, &config.slow_clients);
+    if let Some(limit) =
+        compression::encoded_size_limit(&config.request_decompression, &http_parts.headers)
+    {
+        request_body = request_body.limit(limit);
+    }
+    let http_collected = match request_body.collect().await {
+        Ok(http_collected) => http_collected,
+        Err(BodyError::Hyper(e)) => return Err(e),
+        Err(BodyError::TooSlow(reason)) => {
+            state
+                .connections
+                .slow_clients
+                .fetch_add(1, Ordering::Relaxed);
+            eprintln!("slow client: {}: {}", conn_info.peer_addr, reason);
+            let mut res = error_response(hyper::StatusCode::REQUEST_TIMEOUT);
+            if http_parts.version < Version::HTTP_2 {
+                res.headers_mut()
+                    .insert(hyper::header::CONNECTION, HeaderValue::from_static("close"));
+            }
+            return Ok(res);
+        }
+        Err(BodyError::TooLarge) => {
+            let mut res = error_response(hyper::StatusCode::PAYLOAD_TOO_LARGE);
+            // The rest of the body is left unread
+            if http_parts.version < Version::HTTP_2 {
+                res.headers_mut()
+                    .insert(hyper::header::CONNECTION, HeaderValue::from_static("close"));
+            }
+            return Ok(res);
+        }
+    };
+    let http_trailers = http_collected
+        .trailers()
+        .map(to_grpc_headers)
+        .unwrap_or_default();
+    let http_body: Vec<u8> = match compression::decompress_request(
+        &config.request_decompression,
+        &mut http_parts.headers,
+        http_collected.to_bytes().to_vec(),
+    ) {
+        Ok(http_body) => http_body,
+        Err(e) => {
+            eprintln!("request body error: {}", e);
+            return Ok(error_response(e.status()));
+        }
+    };
+    let grpc_request = to_grpc_request(&http_parts, http_body, http_trailers, &conn_info);
+
+    // Upgrades go over streams of their own, never cached or coalesced
+    if let Some(on_upgrade) = on_upgrade {
+        let websocket = upgrade_protocol
+            .as_ref()
+            .is_some_and(|protocol| protocol.as_bytes().eq_ignore_ascii_case(b"websocket"));
+        let result = if config.websocket.enabled && websocket {
+            websocket::forward(
+                &state.pool,
+                grpc_request,
+                &http_parts.headers,
+                on_upgrade,
+                &state.connections,
+                slot,
+                &config.websocket,
+            )
+            .await
+        } else {
+            upgrade::forward(
+                &state.pool,
+                grpc_request,
+                upgrade_protocol,
+                on_upgrade,
+                &state.connections,
+                slot,
+            )
+            .await
+        };
+        return Ok(result.unwrap_or_else(|status| {
+            eprintln!("grpc error: {}", status);
+            error_response(hyper::StatusCode::BAD_GATEWAY)
+        }));
+    }
+
+    // Send request to grpc server, through the cache when it applies
+    let cache_uri = format!(
+        "{}{}",
+        http_host.as_deref().unwrap_or_default(),
+        http_parts.uri.path_and_query().map_or("/", |p| p.as_str())
+    );
+    let forward = |grpc_request: HttpRequest| async {
+        let send = |grpc_request: HttpRequest| async {
+            let grpc_response: tonic::Response<HttpResponse> =
+                state.pool.handle(grpc_request).await?;
+            Ok(grpc_response.into_inner())
+        };
+        match &state.coalescer {
+            Some(coalescer) => coalescer.run(grpc_request, send).await,
+            None => send(grpc_request).await,
+        }
+    };
+    let grpc_result = if sse::wants_stream(&config.sse, http_parts.uri.path(), &http_parts.headers)
+    {
+        // Event streams skip the cache, coalescing and compression, other
+        // responses continue below
+        match sse::forward(&state.pool, grpc_request, &config.sse, &state.connections).await {
+            Ok(Streamed::Events(res)) => return Ok(res),
+            Ok(Streamed::Complete(grpc_response)) => Ok((grpc_response, CacheStatus::Bypass)),
+            Err(status) => Err(status),
+        }
+    } else {
+        match &state.cache {
+            Some(cache)
+                if ResponseCache::is_cacheable_request(&http_method_ref, &http_parts.headers) =>
+            {
+                cache
+                    .fetch(
+                        format!("{} {}", http_method_ref, cache_uri),
+                        &http_parts.headers,
+                        grpc_request,
+                        forward,
+                    )
+                    .await
+            }
+            _ => forward(grpc_request)
+                .await
+                .map(|grpc_response| (grpc_response, CacheStatus::Bypass)),
+        }
+    };
+    let (grpc_response_ref, cache_status) = match grpc_result {
+        Ok(grpc_result) => grpc_result,
+        Err(status) => {
+            eprintln!("grpc error: {}", status);
+            return Ok(error_response(hyper::StatusCode::BAD_GATEWAY));
+        }
+    };
+
+    if let Some(cache) = &state.cache {
+        // Unsafe methods change the resource, stored responses are outdated
+        if !http_method_ref.is_safe() && grpc_response_ref.status < 400 {
+            cache
+                .invalidate(&format!("{} {}", hyper::Method::GET, cache_uri))
+                .await;
+        }
+    }
+
+    // Generate http response from grpc response
+    let res_status_code = to_http_status(grpc_response_ref.status);
+    let res_version = to_http_version(&grpc_response_ref.version);
+
+    let mut res_headers = to_http_headers(grpc_response_ref.headers);
+    let res_trailers = to_http_headers(grpc_response_ref.trailers);
+    let mut res_body_bytes = grpc_response_ref.body;
+
+    // Hop-by-hop headers from the worker must not reach the client
+    proxy_headers::strip_hop_by_hop(&mut res_headers);
+
+    if state.cache.is_some() {
+        res_headers.insert("x-cache", HeaderValue::from_static(cache_status.as_str()));
+    }
+    compression::compress_response(
+        &config.compression,
+        &http_method_ref,
+        compression_encoding,
+        res_status_code,
+        &mut res_headers,
+        &mut res_body_bytes,
+    );
+
+    let res_body = if res_trailers.is_empty() {
+        body::full(res_body_bytes)
+    } else {
+        // HTTP/1 only sends trailers announced in the Trailer header
+        if !res_headers.contains_key(hyper::header::TRAILER) {
+            let names: Vec<&str> = res_trailers.keys().map(|name| name.as_str()).collect();
+            if let Ok(value) = HeaderValue::from_str(&names.join(", ")) {
+                res_headers.insert(hyper::header::TRAILER, value);
+            }
+        }
+        body::with_trailers(res_body_bytes, res_trailers)
+    };
+
+    let mut res = Response::builder()
+        .version(res_version)
+        .status(res_status_code)
+        .body(res_body)
+        .unwrap();
+    *res.headers_mut() = res_headers;
+
+    Ok(res)
+}
+
+// Timeouts configured in milliseconds, disabled when 0
+fn millis(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+fn error_response(status: hyper::StatusCode) -> Response<ResponseBody> {
+    let reason = status.canonical_reason().unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "text/plain")
+        .body(body::full(reason))
+        .unwrap()
+}
+
+// Serves until shutdown; see `Gateway::serve`
+async fn serve(gateway: Gateway) -> Result<(), String> {
+    let Gateway {
+        state,
+        service,
+        listeners,
+        admin_addr,
+        shared,
+        readiness,
+        handle_signals,
+    } = gateway;
+
+    if state.config.health_check.enabled {
+        let state = state.clone();
+        tokio::spawn(async move {
+            state
+                .pool
+                .run_health_checks(state.config.health_check.clone())
+                .await
+        });
+    }
+
+    if let Some(admin_addr) = admin_addr {
+        tokio::spawn(admin::serve(admin_addr, state.clone()));
+    }
+
+    let slow_clients = &state.config.slow_clients;
+    let mut server =
+        hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+
+    server
+        .http1()
+        .preserve_header_case(true)
+        .title_case_headers(true)
+        .max_headers(100)
+        .timer(TokioTimer::new())
+        .header_read_timeout(millis(slow_clients.header_read_timeout_ms))
+        .keep_alive(true);
+
+    server
+        .http2()
+        .max_concurrent_streams(200)
+        .timer(TokioTimer::new())
+        // A ping nobody waits for is useless, a timeout of 0 disables them
+        .keep_alive_interval(
+            millis(slow_clients.http2_keep_alive_interval_ms)
+                .filter(|_| slow_clients.http2_keep_alive_timeout_ms > 0),
+        )
+        .keep_alive_timeout(Duration::from_millis(
+            slow_clients.http2_keep_alive_timeout_ms,
+        ));
+    let server = Arc::new(server);
+
+    // Set once the listeners close, connections then close as soon as they
+    // are idle
+    let (drain, draining) = tokio::sync::watch::channel(false);
+    let conn_limits = state.config.connections.clone();
+    let connection_limit = (conn_limits.max_connections > 0)
+        .then(|| Arc::new(tokio::sync::Semaphore::new(conn_limits.max_connections)));
+    let (mut signals, mut upgrade_signal) = if handle_signals {
+        let signals = Signals::new().map_err(|e| format!("signal handler error: {}", e))?;
+        let upgrade_signal = signal(SignalKind::user_defined2())
+            .map_err(|e| format!("signal handler error: {}", e))?;
+        (Some(signals), Some(upgrade_signal))
+    } else {
+        (None, None)
+    };
+    if let Some(readiness) = readiness {
+        readiness.instance_ready();
+    }
+
+    // Armed when shutdown starts, the listeners close once it elapses
+    let pre_stop_delay = Duration::from_millis(state.config.shutdown.pre_stop_delay_ms);
+    let mut pre_stop = pin!(tokio::time::sleep(Duration::ZERO));
+    // Armed when accepting fails for lack of file descriptors
+    let mut accept_backoff = None;
+    let mut accept_resume = pin!(tokio::time::sleep(Duration::ZERO));
+    let mut accept_paused = false;
+
+    loop {
+        tokio::select! {
+            (permit, (conn, index, _)) = async {
+                // Waiting for a permit leaves new clients in the listen backlog
+                let permit = match &connection_limit {
+                    Some(limit) if conn_limits.over_limit == OverLimit::Pause => Some(
+                        limit.clone().acquire_owned().await.expect("the semaphore is never closed"),
+                    ),
+                    _ => None,
+                };
+                let conn = futures::future::select_all(
+                    listeners.iter().map(|listener| Box::pin(listener.accept())),
+                )
+                .await;
+                (permit, conn)
+            }, if !accept_paused => {
+                let listener = listeners[index].clone();
+                let accepted = match conn {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        accept_backoff = limits::accept_backoff(&e, accept_backoff);
+                        match accept_backoff {
+                            Some(delay) => {
+                                eprintln!(
+                                    "accept error on {}: {}, pausing for {:?}",
+                                    listener.name, e, delay
+                                );
+                                let resume = tokio::time::Instant::now() + delay;
+                                accept_resume.as_mut().reset(resume);
+                                accept_paused = true;
+                            }
+                            None => eprintln!("accept error on {}: {}", listener.name, e),
+                        }
+                        continue;
+                    }
+                };
+                accept_backoff = None;
+                let mut peer_addr = accepted.peer_addr;
+
+                let permit = match (permit, &connection_limit) {
+                    (Some(permit), Some(limit)) => {
+                        if limit.available_permits() == 0 {
+                            eprintln!(
+                                "connection limit of {} reached, accepting paused",
+                                conn_limits.max_connections
+                            );
+                        }
+                        Some(permit)
+                    }
+                    (_, Some(limit)) => match limit.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            state.connections.rejected.fetch_add(1, Ordering::Relaxed);
+                            eprintln!(
+                                "connection limit of {} reached, closing connection: {}",
+                                conn_limits.max_connections, peer_addr
+                            );
+                            continue;
+                        }
+                    },
+                    (_, None) => None,
+                };
+                println!("incomming connection accepted: {} on {}", peer_addr, listener.name);
+
+                let state = state.clone();
+                let server = server.clone();
+                let service = service.clone();
+                let mut draining = draining.clone();
+                let slot = ConnectionSlot::new(state.connections.open(), permit);
+                let max_age = tokio::time::Instant::now() + Duration::from_millis(conn_limits.max_age_ms);
+
+                // The PROXY header and TLS handshake are read off the accept loop
+                tokio::spawn(async move {
+                    let mut stream = accepted.stream;
+                    let mut local_addr = accepted.local_addr;
+                    if let Some(timeout) = listener.proxy_header_timeout(&peer_addr) {
+                        let header =
+                            tokio::time::timeout(timeout, proxy_protocol::read_header(stream)).await;
+                        match header {
+                            Ok(Ok((proxied_stream, addrs))) => {
+                                stream = proxied_stream;
+                                if let Some(addrs) = addrs {
+                                    println!("proxied connection: {} via {}", addrs.source, peer_addr);
+                                    peer_addr = Address::Inet(addrs.source);
+                                    local_addr = Address::Inet(addrs.destination);
+                                }
+                            }
+                            Ok(Err(e)) => {
+                                eprintln!("PROXY protocol error: {}: {}", peer_addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                eprintln!("PROXY protocol error: {}: no header after {:?}", peer_addr, timeout);
+                                return;
+                            }
+                        }
+                    }
+
+                    let handshake_timeout = millis(state.config.slow_clients.header_read_timeout_ms);
+                    let handshake = match handshake_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, listener.handshake(stream)).await,
+                        None => Ok(listener.handshake(stream).await),
+                    };
+                    let (stream, tls) = match handshake {
+                        Ok(Ok(handshake)) => handshake,
+                        Ok(Err(e)) => {
+                            eprintln!("TLS handshake error: {}: {}", peer_addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            state.connections.slow_clients.fetch_add(1, Ordering::Relaxed);
+                            eprintln!(
+                                "TLS handshake error: {}: not complete after {:?}",
+                                peer_addr,
+                                handshake_timeout.unwrap_or_default()
+                            );
+                            return;
+                        }
+                    };
+                    let conn_info = Arc::new(ConnectionInfo::new(
+                        peer_addr.clone(),
+                        local_addr,
+                        tls,
+                        listener.routes.clone(),
+                    ));
+
+                    let stream = SlowClientIo::new(
+                        stream,
+                        &state.config.slow_clients,
+                        state.connections.clone(),
+                    );
+                    let stream = hyper_util::rt::TokioIo::new(stream);
+                    let activity = Arc::new(ConnectionActivity::default());
+                    let svc = Svc {
+                        state: state.clone(),
+                        conn_info,
+                        activity: activity.clone(),
+                        slot,
+                        service,
+                    };
+                    let mut conn = pin!(server.serve_connection_with_upgrades(stream, svc));
+
+                    // Idle HTTP/1 connections are closed and HTTP/2 clients
+                    // get a GOAWAY, in-flight requests complete
+                    let conn_limits = &state.config.connections;
+                    let idle_timeout = Duration::from_millis(conn_limits.idle_timeout_ms);
+                    let closing = tokio::select! {
+                        result = conn.as_mut() => Err(result),
+                        _ = draining.wait_for(|draining| *draining) => Ok("draining"),
+                        _ = tokio::time::sleep_until(max_age), if conn_limits.max_age_ms > 0 => {
+                            Ok("max age reached")
+                        }
+                        _ = activity.idle(idle_timeout), if conn_limits.idle_timeout_ms > 0 => {
+                            Ok("idle timeout")
+                        }
+                        _ = activity.served(conn_limits.max_requests), if conn_limits.max_requests > 0 => {
+                            Ok("max requests reached")
+                        }
+                    };
+                    let result = match closing {
+                        Ok(reason) => {
+                            println!("closing connection: {}: {}", peer_addr, reason);
+                            conn.as_mut().graceful_shutdown();
+                            // Closing before the first request cancels the
+                            // protocol detection, which is no error here
+                            conn.await.or_else(|err| match err.downcast_ref::<std::io::Error>() {
+                                Some(e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
+                                _ => Err(err),
+                            })
+                        }
+                        Err(result) => result,
+                    };
+                    if let Err(err) = result {
+                        if slow_client::is_timeout(&*err) {
+                            state.connections.slow_clients.fetch_add(1, Ordering::Relaxed);
+                        }
+                        eprintln!("connection error: {}", err);
+                    }
+                    println!("connection dropped: {}", peer_addr);
+                });
+            },
+            _ = accept_resume.as_mut(), if accept_paused => accept_paused = false,
+            reason = termination(&mut signals) => {
+                // A second signal aborts, even before the listeners close
+                if state.draining.load(Ordering::Relaxed) {
+                    eprintln!("{} received while draining, aborting...", reason.name());
+                    log_dropped(&state);
+                    return Ok(());
+                }
+                start_shutdown(&state, reason.name(), pre_stop_delay);
+                pre_stop.as_mut().reset(tokio::time::Instant::now() + pre_stop_delay);
+            }
+            _ = state.shutdown.notified(), if !state.draining.load(Ordering::Relaxed) => {
+                start_shutdown(&state, "shutdown request", pre_stop_delay);
+                pre_stop.as_mut().reset(tokio::time::Instant::now() + pre_stop_delay);
+            }
+            _ = upgrade_requested(&mut upgrade_signal), if !state.draining.load(Ordering::Relaxed) => {
+                start_upgrade(&state, &listeners, shared);
+            }
+            _ = state.upgrade.notified(), if !state.draining.load(Ordering::Relaxed) => {
+                start_upgrade(&state, &listeners, shared);
+            }
+            _ = pre_stop.as_mut(), if state.draining.load(Ordering::Relaxed) => break,
+        }
+    }
+
+    drop(listeners);
+    println!(
+        "listeners closed, draining {} connections",
+        state.connections.active.load(Ordering::Relaxed)
+    );
+
+    // In-flight requests complete until the drain timeout
+    drain.send_replace(true);
+    let drain_timeout = Duration::from_millis(state.config.shutdown.drain_timeout_ms);
+    tokio::select! {
+        _ = state.connections.all_closed() => {
+            println!("Gracefully shutdown!");
+            return Ok(());
+        },
+        _ = tokio::time::sleep(drain_timeout) => {
+            eprintln!("drain timeout of {:?} reached, aborting...", drain_timeout);
+        }
+        reason = termination(&mut signals) => {
+            eprintln!("{} received while draining, aborting...", reason.name());
+        }
+    }
+    log_dropped(&state);
+    Ok(())
+}
+
+fn log_dropped(state: &AppState) {
+    eprintln!(
+        "dropped {} in-flight requests on {} connections",
+        state.connections.in_flight_requests.load(Ordering::Relaxed),
+        state.connections.active.load(Ordering::Relaxed),
+    );
+}
+
+// Waits for a termination signal, forever when signals are left to the
+// embedding application
+async fn termination(signals: &mut Option<Signals>) -> Termination {
+    match signals {
+        Some(signals) => signals.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn upgrade_requested(upgrade_signal: &mut Option<Signal>) {
+    match upgrade_signal {
+        Some(upgrade_signal) => {
+            upgrade_signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Starts a new process serving on the same listeners, then shuts down once
+// it is ready.
+fn start_upgrade(state: &Arc<AppState>, listeners: &[Arc<Listener>], shared: bool) {
+    // Sibling instances have listeners of their own this one cannot pass
+    if shared {
+        eprintln!("upgrades are only supported in the single runtime mode");
+        return;
+    }
+    if state.upgrading.swap(true, Ordering::Relaxed) {
+        println!("upgrade already in progress");
+        return;
+    }
+    println!("upgrade requested, starting a new process");
+
+    let state = state.clone();
+    let listeners = listeners.to_vec();
+    let timeout = Duration::from_millis(state.config.shutdown.upgrade_timeout_ms);
+    tokio::spawn(async move {
+        let fds = listeners.iter().map(|listener| listener.raw_fd()).collect();
+        match handoff::spawn_successor(fds, timeout).await {
+            Ok(pid) => {
+                for listener in &listeners {
+                    listener.hand_off();
+                }
+                println!("process {} is serving, handing over", pid);
+                state.shutdown.notify_one();
+            }
+            Err(e) => {
+                eprintln!("upgrade failed: {}", e);
+                state.upgrading.store(false, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+fn start_shutdown(state: &AppState, reason: &str, pre_stop_delay: Duration) {
+    state.draining.store(true, Ordering::Relaxed);
+    println!(
+        "{} received, readiness failing, closing the listeners in {:?}",
+        reason, pre_stop_delay
+    );
+}
+
+#[derive(Debug, Clone)]
+struct Svc {
+    state: Arc<AppState>,
+    conn_info: Arc<ConnectionInfo>,
+    activity: Arc<ConnectionActivity>,
+    // Dropped with the connection, or with its tunnel once upgraded
+    slot: Arc<ConnectionSlot>,
+    service: GatewayService,
+}
+
+impl Service<Request<Incoming>> for Svc {
+    type Response = Response<ResponseBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        let state = self.state.clone();
+        // Layers find the connection of a request in its extensions
+        req.extensions_mut().insert(self.conn_info.clone());
+        req.extensions_mut().insert(self.slot.clone());
+        let service = self.service.clone();
+        // Connection: close only exists in HTTP/1, whatever the worker says
+        let version = req.version();
+        let cors = (!state.config.cors.is_empty()).then(|| CorsRequest::new(&req));
+        let activity_guard = self.activity.start_request();
+        let max_requests = state.config.connections.max_requests;
+        let last_request = max_requests > 0 && self.activity.requests() >= max_requests;
+        // Tunnels keep the connection, whatever happens to keep-alive
+        let connect = req.method() == hyper::Method::CONNECT;
+        Box::pin(async move {
+            let _activity_guard = activity_guard;
+            let _request_guard = state.connections.start_request();
+            let mut result = service.oneshot(req).await.or_else(middleware::recover);
+            if let (Ok(res), Some(cors)) = (&mut result, &cors) {
+                cors.add_response_headers(&state.config.cors, res.headers_mut());
+            }
+
+            // Keep-alive clients reconnect elsewhere once draining starts,
+            // or here after the last request the connection may serve
+            if state.draining.load(Ordering::Relaxed) || last_request {
+                if let Ok(res) = &mut result {
+                    if version < Version::HTTP_2
+                        && res.status() != hyper::StatusCode::SWITCHING_PROTOCOLS
+                        && !(connect && res.status().is_success())
+                    {
+                        res.headers_mut()
+                            .insert(hyper::header::CONNECTION, HeaderValue::from_static("close"));
+                    }
+                }
+            }
+            result
+        })
+    }
+}
@@ -0,0 +1,158 @@
+use std::io;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+// Pause after the first accept failing for lack of resources, doubled on
+// every following failure
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Pause before accepting again after `error`, `None` when the next
+/// connection can be accepted straight away.
+///
+/// Running out of file descriptors or memory backs off from
+/// `previous`; errors concerning a single connection, such as a client
+/// resetting it before it was accepted, do not.
+pub fn accept_backoff(error: &io::Error, previous: Option<Duration>) -> Option<Duration> {
+    let exhausted = matches!(
+        error.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    );
+    if !exhausted {
+        return None;
+    }
+    Some(match previous {
+        Some(previous) => (previous * 2).min(MAX_ACCEPT_BACKOFF),
+        None => MIN_ACCEPT_BACKOFF,
+    })
+}
+
+/// Requests made on one client connection, checked against the idle
+/// timeout and `max_requests`.
+#[derive(Debug, Default)]
+pub struct ConnectionActivity {
+    requests: AtomicU64,
+    in_flight: AtomicUsize,
+    changed: Notify,
+}
+
+impl ConnectionActivity {
+    /// Records a request in flight until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> ActivityGuard {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_waiters();
+        ActivityGuard(self.clone())
+    }
+
+    /// Requests made on the connection so far.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Completes once the connection went `timeout` without a request in
+    /// flight.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let mut changed = pin!(self.changed.notified());
+            changed.as_mut().enable();
+            if self.in_flight.load(Ordering::Relaxed) > 0 {
+                changed.await;
+            } else if tokio::time::timeout(timeout, changed).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Completes once `max` requests were made on the connection.
+    pub async fn served(&self, max: u64) {
+        loop {
+            let mut changed = pin!(self.changed.notified());
+            changed.as_mut().enable();
+            if self.requests() >= max {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+pub struct ActivityGuard(Arc<ConnectionActivity>);
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_on_exhausted_resources() {
+        let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+
+        let mut backoff = accept_backoff(&emfile, None);
+        assert_eq!(backoff, Some(MIN_ACCEPT_BACKOFF));
+        backoff = accept_backoff(&emfile, backoff);
+        assert_eq!(backoff, Some(MIN_ACCEPT_BACKOFF * 2));
+        for _ in 0..20 {
+            backoff = accept_backoff(&emfile, backoff);
+        }
+        assert_eq!(backoff, Some(MAX_ACCEPT_BACKOFF));
+
+        for errno in [libc::ENFILE, libc::ENOBUFS, libc::ENOMEM] {
+            let error = io::Error::from_raw_os_error(errno);
+            assert_eq!(accept_backoff(&error, None), Some(MIN_ACCEPT_BACKOFF));
+        }
+    }
+
+    #[test]
+    fn no_backoff_on_connection_errors() {
+        for errno in [libc::ECONNABORTED, libc::ECONNRESET, libc::EINTR] {
+            let error = io::Error::from_raw_os_error(errno);
+            assert_eq!(accept_backoff(&error, Some(MAX_ACCEPT_BACKOFF)), None);
+        }
+        let error = io::Error::other("no errno");
+        assert_eq!(accept_backoff(&error, None), None);
+    }
+
+    #[tokio::test]
+    async fn idle_waits_for_requests_in_flight() {
+        let activity = Arc::new(ConnectionActivity::default());
+        let timeout = Duration::from_millis(20);
+
+        let guard = activity.start_request();
+        let idle = tokio::time::timeout(Duration::from_millis(100), activity.idle(timeout));
+        assert!(idle.await.is_err());
+
+        drop(guard);
+        let idle = tokio::time::timeout(Duration::from_millis(100), activity.idle(timeout));
+        assert!(idle.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn served_counts_requests() {
+        let activity = Arc::new(ConnectionActivity::default());
+        let served = tokio::spawn({
+            let activity = activity.clone();
+            async move { activity.served(2).await }
+        });
+
+        drop(activity.start_request());
+        tokio::task::yield_now().await;
+        assert!(!served.is_finished());
+        drop(activity.start_request());
+        tokio::time::timeout(Duration::from_secs(1), served)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(activity.requests(), 2);
+    }
+}
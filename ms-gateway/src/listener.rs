@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{self, ListenerConfig, ProxyProtocolConfig, TlsConfig};
+use crate::connection::{Address, TlsInfo};
+use crate::handoff::{InheritedAddr, InheritedSocket};
+
+/// Byte stream of an accepted connection, plain or TLS, TCP or Unix.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// A bound listener, see [`ListenerConfig`].
+pub struct Listener {
+    pub name: String,
+    socket: Socket,
+    tls: Option<TlsAcceptor>,
+    pub routes: Arc<[String]>,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    // Set once the socket is handed to a new process, which keeps using
+    // the Unix socket file
+    handed_off: AtomicBool,
+}
+
+/// A connection accepted by a [`Listener`], before any TLS handshake.
+pub struct Accepted {
+    pub stream: Box<dyn Io>,
+    pub peer_addr: Address,
+    pub local_addr: Address,
+}
+
+impl Listener {
+    /// Binds the socket of `config`, or takes it from `inherited` when a
+    /// previous process passed one with the same address. TCP sockets are
+    /// bound with `SO_REUSEPORT` when `reuse_port` is set.
+    pub fn bind(
+        config: &ListenerConfig,
+        inherited: &mut Vec<InheritedSocket>,
+        reuse_port: bool,
+    ) -> Result<Listener, String> {
+        let tls = config.tls.as_ref().map(tls_acceptor).transpose()?;
+
+        let (socket, default_name) = match (&config.address, &config.path) {
+            (Some(address), None) => {
+                let listener = match take(inherited, &InheritedAddr::Inet(*address)) {
+                    Some(socket) => tcp_from_inherited(socket),
+                    None => bind_tcp(*address, reuse_port),
+                }
+                .map_err(|e| format!("cannot bind {}: {}", address, e))?;
+                (Socket::Tcp(listener), address.to_string())
+            }
+            (None, Some(path)) => {
+                let listener = match take(inherited, &InheritedAddr::Unix(path.clone())) {
+                    Some(socket) => unix_from_inherited(socket),
+                    None => bind_unix(path, config.mode),
+                }
+                .map_err(|e| format!("cannot bind {}: {}", path.display(), e))?;
+                (
+                    Socket::Unix(listener, path.clone()),
+                    format!("unix:{}", path.display()),
+                )
+            }
+            _ => return Err("a listener needs exactly one of address and path".to_string()),
+        };
+
+        Ok(Listener {
+            name: config.name.clone().unwrap_or(default_name),
+            socket,
+            tls,
+            routes: config.routes.clone().into(),
+            proxy_protocol: config.proxy_protocol.clone(),
+            handed_off: AtomicBool::new(false),
+        })
+    }
+
+    /// Serves an inherited socket no listener is configured for, without
+    /// TLS and on all paths.
+    pub fn from_inherited(inherited: InheritedSocket) -> Result<Listener, String> {
+        let addr = inherited.addr.clone();
+        let (socket, name) = match addr {
+            InheritedAddr::Inet(address) => (
+                Socket::Tcp(tcp_from_inherited(inherited).map_err(|e| e.to_string())?),
+                address.to_string(),
+            ),
+            InheritedAddr::Unix(path) => (
+                Socket::Unix(
+                    unix_from_inherited(inherited).map_err(|e| e.to_string())?,
+                    path.clone(),
+                ),
+                format!("unix:{}", path.display()),
+            ),
+        };
+        Ok(Listener {
+            name,
+            socket,
+            tls: None,
+            routes: Vec::new().into(),
+            proxy_protocol: None,
+            handed_off: AtomicBool::new(false),
+        })
+    }
+
+    pub fn raw_fd(&self) -> RawFd {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.as_raw_fd(),
+            Socket::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+
+    /// Marks the socket as used by a new process.
+    pub fn hand_off(&self) {
+        self.handed_off.store(true, Ordering::Relaxed);
+    }
+
+    /// How long `peer_addr` gets to send a PROXY protocol header, or `None`
+    /// when it connects directly and sends none.
+    pub fn proxy_header_timeout(&self, peer_addr: &Address) -> Option<Duration> {
+        let config = self.proxy_protocol.as_ref()?;
+        let trusted = match peer_addr.ip() {
+            Some(ip) => config::contains_addr(&config.trusted_sources, ip),
+            None => true,
+        };
+        trusted.then(|| Duration::from_millis(config.timeout_ms))
+    }
+
+    pub async fn accept(&self) -> io::Result<Accepted> {
+        match &self.socket {
+            Socket::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                let local_addr = stream.local_addr()?;
+                Ok(Accepted {
+                    stream: Box::new(stream),
+                    peer_addr: Address::Inet(peer_addr),
+                    local_addr: Address::Inet(local_addr),
+                })
+            }
+            Socket::Unix(listener, path) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                Ok(Accepted {
+                    stream: Box::new(stream),
+                    peer_addr: Address::Unix(peer_addr.as_pathname().map(Path::to_path_buf)),
+                    local_addr: Address::Unix(Some(path.clone())),
+                })
+            }
+        }
+    }
+
+    /// Runs the TLS handshake when the listener has TLS configured.
+    pub async fn handshake(
+        &self,
+        stream: Box<dyn Io>,
+    ) -> io::Result<(Box<dyn Io>, Option<TlsInfo>)> {
+        let Some(acceptor) = &self.tls else {
+            return Ok((stream, None));
+        };
+
+        let stream = acceptor.accept(stream).await?;
+        let (_, session) = stream.get_ref();
+        let info = TlsInfo {
+            protocol: session
+                .protocol_version()
+                .and_then(|version| version.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            cipher: session
+                .negotiated_cipher_suite()
+                .and_then(|suite| suite.suite().as_str())
+                .unwrap_or_default()
+                .to_string(),
+            sni: session.server_name().unwrap_or_default().to_string(),
+        };
+        Ok((Box::new(stream), Some(info)))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Socket::Unix(_, path) = &self.socket {
+            if !self.handed_off.load(Ordering::Relaxed) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn take(inherited: &mut Vec<InheritedSocket>, addr: &InheritedAddr) -> Option<InheritedSocket> {
+    let index = inherited.iter().position(|socket| &socket.addr == addr)?;
+    Some(inherited.remove(index))
+}
+
+fn tcp_from_inherited(inherited: InheritedSocket) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::from(inherited.fd);
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+fn unix_from_inherited(inherited: InheritedSocket) -> io::Result<UnixListener> {
+    let listener = std::os::unix::net::UnixListener::from(inherited.fd);
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+fn bind_tcp(address: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(address),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    // A socket file left by a previous run would make bind fail
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+fn tls_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let read_error = |path: &Path, e: io::Error| format!("cannot read {}: {}", path.display(), e);
+
+    let mut cert_reader = BufReader::new(
+        File::open(&config.cert_path).map_err(|e| read_error(&config.cert_path, e))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| read_error(&config.cert_path, e))?;
+
+    let mut key_reader =
+        BufReader::new(File::open(&config.key_path).map_err(|e| read_error(&config.key_path, e))?);
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(|e| read_error(&config.key_path, e))?
+        .ok_or_else(|| format!("no private key in {}", config.key_path.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid TLS certificate or key: {}", e))?;
+    // Both protocols are served by the same connection builder
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
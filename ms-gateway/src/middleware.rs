@@ -0,0 +1,175 @@
+//! Tower services every request goes through, with the forwarding to the
+//! workers as the innermost one.
+//!
+//! Layers read the connection a request came on from its extensions, as
+//! an `Arc<ConnectionInfo>`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::body::Incoming;
+use hyper::{Request, Response, StatusCode};
+use tower::limit::ConcurrencyLimitLayer;
+use tower::load_shed::error::Overloaded;
+use tower::load_shed::LoadShedLayer;
+use tower::timeout::error::Elapsed;
+use tower::timeout::TimeoutLayer;
+use tower::util::BoxCloneService;
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::body::ResponseBody;
+use crate::config::MiddlewareConfig;
+use crate::connection::ConnectionInfo;
+use crate::{error_response, handle_request, AppState};
+
+/// Request path of a gateway, as wrapped by each layer.
+pub type GatewayService = BoxCloneService<Request<Incoming>, Response<ResponseBody>, BoxError>;
+
+// Wraps the service built so far in a layer added through the builder
+pub(crate) type LayerFn = Box<dyn FnOnce(GatewayService) -> GatewayService + Send>;
+
+/// Hook run around every request, a simpler alternative to a tower layer.
+pub trait Middleware: Send + Sync + 'static {
+    /// Called before the request goes on. Returning a response answers
+    /// the request without calling a worker.
+    fn on_request(
+        &self,
+        _request: &mut Request<Incoming>,
+        _conn_info: &ConnectionInfo,
+    ) -> Option<Response<ResponseBody>> {
+        None
+    }
+
+    /// Called on every response coming back, including the ones this
+    /// middleware answered itself.
+    fn on_response(&self, _response: &mut Response<ResponseBody>) {}
+}
+
+pub(crate) fn layer_fn<L>(layer: L) -> LayerFn
+where
+    L: Layer<GatewayService> + Send + 'static,
+    L::Service:
+        Service<Request<Incoming>, Response = Response<ResponseBody>> + Clone + Send + 'static,
+    <L::Service as Service<Request<Incoming>>>::Error: Into<BoxError>,
+    <L::Service as Service<Request<Incoming>>>::Future: Send + 'static,
+{
+    Box::new(move |inner| BoxCloneService::new(layer.layer(inner).map_err(Into::into)))
+}
+
+/// Builds the request path: the builder layers, outermost first, around the
+/// layers of `config`, around the forwarding to the workers.
+pub(crate) fn stack(
+    state: Arc<AppState>,
+    config: &MiddlewareConfig,
+    layers: Vec<LayerFn>,
+) -> GatewayService {
+    let mut service = BoxCloneService::new(Forward(state));
+    if config.concurrency_limit > 0 {
+        service = layer_fn(ConcurrencyLimitLayer::new(config.concurrency_limit))(service);
+        if config.load_shed {
+            service = layer_fn(LoadShedLayer::new())(service);
+        }
+    }
+    if config.timeout_ms > 0 {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        service = layer_fn(TimeoutLayer::new(timeout))(service);
+    }
+    for layer in layers.into_iter().rev() {
+        service = layer(service);
+    }
+    service
+}
+
+/// Answers the errors of the layers: 504 on a timeout, 503 when
+/// overloaded and 500 otherwise. Errors of the client connection are
+/// left to close it.
+pub(crate) fn recover(err: BoxError) -> Result<Response<ResponseBody>, BoxError> {
+    if err.is::<hyper::Error>() {
+        return Err(err);
+    }
+    let status = if err.is::<Elapsed>() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else if err.is::<Overloaded>() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        eprintln!("middleware error: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok(error_response(status))
+}
+
+// Innermost service, forwarding to the workers
+#[derive(Debug, Clone)]
+struct Forward(Arc<AppState>);
+
+impl Service<Request<Incoming>> for Forward {
+    type Response = Response<ResponseBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let state = self.0.clone();
+        Box::pin(async move {
+            let conn_info = connection_info(&req);
+            Ok(handle_request(req, state, conn_info).await?)
+        })
+    }
+}
+
+fn connection_info(req: &Request<Incoming>) -> Arc<ConnectionInfo> {
+    req.extensions()
+        .get::<Arc<ConnectionInfo>>()
+        .cloned()
+        .expect("the connection info is added to every request")
+}
+
+/// Layer running a [`Middleware`].
+pub(crate) struct MiddlewareLayer(pub Arc<dyn Middleware>);
+
+impl<S> Layer<S> for MiddlewareLayer {
+    type Service = MiddlewareService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MiddlewareService {
+            inner,
+            middleware: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct MiddlewareService<S> {
+    inner: S,
+    middleware: Arc<dyn Middleware>,
+}
+
+impl Service<Request<Incoming>> for MiddlewareService<GatewayService> {
+    type Response = Response<ResponseBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Incoming>) -> Self::Future {
+        let middleware = self.middleware.clone();
+        let conn_info = connection_info(&req);
+        let response: Self::Future = match middleware.on_request(&mut req, &conn_info) {
+            Some(res) => Box::pin(async { Ok(res) }),
+            None => self.inner.call(req),
+        };
+        Box::pin(async move {
+            let mut res = response.await?;
+            middleware.on_response(&mut res);
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,379 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::net::UnixStream;
+use tonic::transport::Channel;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+
+// httpgrpc - protos
+use protos::httpgrpc::{HttpRequest, HttpResponse, UpgradeFrame, WebSocketFrame};
+
+use crate::config::{GrpcConfig, HealthCheckConfig};
+use crate::worker_client::WorkerClient;
+
+// Consecutive failures after which an endpoint stops receiving requests
+const FAILURE_THRESHOLD: u32 = 3;
+// Time an unhealthy endpoint is left alone before being tried again
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndpointState {
+    Active,
+    /// Keeps serving its in-flight requests but gets no new ones.
+    Draining,
+}
+
+/// A worker the executor can send requests to.
+#[derive(Debug)]
+pub struct WorkerEndpoint {
+    pub id: u64,
+    pub uri: String,
+    client: WorkerClient,
+    channel: Channel,
+    weight: AtomicU32,
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    consecutive_failures: AtomicU32,
+    unhealthy_since: Mutex<Option<Instant>>,
+    /// Last answer of the health service was not-serving.
+    not_serving: AtomicBool,
+}
+
+/// Snapshot of an endpoint, as reported by the admin API.
+#[derive(Debug, Serialize)]
+pub struct EndpointStatus {
+    pub id: u64,
+    pub uri: String,
+    pub weight: u32,
+    pub state: EndpointState,
+    pub healthy: bool,
+    pub in_flight: usize,
+    pub requests: u64,
+    pub errors: u64,
+}
+
+impl WorkerEndpoint {
+    pub fn state(&self) -> EndpointState {
+        if self.draining.load(Ordering::Relaxed) {
+            EndpointState::Draining
+        } else {
+            EndpointState::Active
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        if self.not_serving.load(Ordering::Relaxed) {
+            return false;
+        }
+        match *self.unhealthy_since.lock().unwrap() {
+            Some(since) => since.elapsed() >= UNHEALTHY_COOLDOWN,
+            None => true,
+        }
+    }
+
+    pub fn status(&self) -> EndpointStatus {
+        EndpointStatus {
+            id: self.id,
+            uri: self.uri.clone(),
+            weight: self.weight.load(Ordering::Relaxed),
+            state: self.state(),
+            healthy: self.is_healthy(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_result(&self, success: bool) {
+        if success {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            *self.unhealthy_since.lock().unwrap() = None;
+            return;
+        }
+
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= FAILURE_THRESHOLD {
+            *self.unhealthy_since.lock().unwrap() = Some(Instant::now());
+        }
+    }
+}
+
+// Decrements the in-flight count even when the request future is dropped.
+struct InFlightGuard<'a>(&'a WorkerEndpoint);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Set of worker endpoints requests are balanced over.
+///
+/// Each request goes to the healthy active endpoint with the fewest
+/// in-flight requests relative to its weight. Endpoints can be added,
+/// removed, drained and reweighted while the executor runs.
+#[derive(Debug)]
+pub struct WorkerPool {
+    grpc_config: GrpcConfig,
+    endpoints: RwLock<Vec<Arc<WorkerEndpoint>>>,
+    next_id: AtomicU64,
+    next_pick: AtomicUsize,
+}
+
+impl WorkerPool {
+    pub fn new(grpc_config: GrpcConfig) -> WorkerPool {
+        WorkerPool {
+            grpc_config,
+            endpoints: RwLock::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            next_pick: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds an endpoint; the connection is established on first use.
+    ///
+    /// `unix:/path/to/socket` endpoints connect to a worker on the same host
+    /// through a Unix domain socket.
+    pub fn add(&self, uri: &str, weight: u32) -> Result<EndpointStatus, String> {
+        let channel = match uri.strip_prefix("unix:") {
+            Some(path) => unix_channel(PathBuf::from(path)),
+            None => tonic::transport::Endpoint::from_shared(uri.to_string())
+                .map_err(|e| format!("invalid worker endpoint {}: {}", uri, e))?
+                .connect_lazy(),
+        };
+
+        let endpoint = Arc::new(WorkerEndpoint {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            uri: uri.to_string(),
+            client: WorkerClient::new(channel.clone(), &self.grpc_config),
+            channel,
+            weight: AtomicU32::new(weight),
+            draining: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            unhealthy_since: Mutex::new(None),
+            not_serving: AtomicBool::new(false),
+        });
+        let status = endpoint.status();
+        self.endpoints.write().unwrap().push(endpoint);
+        Ok(status)
+    }
+
+    /// Removes an endpoint at once; its in-flight requests still complete.
+    pub fn remove(&self, id: u64) -> Option<EndpointStatus> {
+        let mut endpoints = self.endpoints.write().unwrap();
+        let index = endpoints.iter().position(|endpoint| endpoint.id == id)?;
+        Some(endpoints.remove(index).status())
+    }
+
+    pub fn drain(&self, id: u64) -> Option<EndpointStatus> {
+        let endpoint = self.get(id)?;
+        endpoint.draining.store(true, Ordering::Relaxed);
+        Some(endpoint.status())
+    }
+
+    pub fn set_weight(&self, id: u64, weight: u32) -> Option<EndpointStatus> {
+        let endpoint = self.get(id)?;
+        endpoint.weight.store(weight, Ordering::Relaxed);
+        Some(endpoint.status())
+    }
+
+    pub fn statuses(&self) -> Vec<EndpointStatus> {
+        self.endpoints
+            .read()
+            .unwrap()
+            .iter()
+            .map(|endpoint| endpoint.status())
+            .collect()
+    }
+
+    fn get(&self, id: u64) -> Option<Arc<WorkerEndpoint>> {
+        self.endpoints
+            .read()
+            .unwrap()
+            .iter()
+            .find(|endpoint| endpoint.id == id)
+            .cloned()
+    }
+
+    fn pick(&self) -> Option<Arc<WorkerEndpoint>> {
+        let endpoints = self.endpoints.read().unwrap();
+        let candidates: Vec<&Arc<WorkerEndpoint>> = endpoints
+            .iter()
+            .filter(|endpoint| {
+                endpoint.state() == EndpointState::Active
+                    && endpoint.weight.load(Ordering::Relaxed) > 0
+                    && endpoint.is_healthy()
+            })
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        // Start from a rotating offset so ties are spread evenly
+        let offset = self.next_pick.fetch_add(1, Ordering::Relaxed);
+        (0..candidates.len())
+            .map(|i| candidates[(offset + i) % candidates.len()])
+            .min_by(|a, b| load(a).total_cmp(&load(b)))
+            .cloned()
+    }
+
+    /// Sends a request to the selected endpoint.
+    pub async fn handle(
+        &self,
+        request: HttpRequest,
+    ) -> Result<tonic::Response<HttpResponse>, tonic::Status> {
+        let Some(endpoint) = self.pick() else {
+            return Err(tonic::Status::unavailable("no worker endpoint available"));
+        };
+
+        endpoint.requests.fetch_add(1, Ordering::Relaxed);
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _guard = InFlightGuard(&endpoint);
+
+        let result = endpoint.client.handle(request).await;
+        endpoint.record_result(!matches!(&result, Err(status) if is_endpoint_failure(status)));
+        result
+    }
+
+    /// Sends a request with a streamed response to the selected endpoint,
+    /// counted in flight until the response starts.
+    pub async fn handle_stream(
+        &self,
+        request: HttpRequest,
+    ) -> Result<tonic::Response<tonic::Streaming<HttpResponse>>, tonic::Status> {
+        let Some(endpoint) = self.pick() else {
+            return Err(tonic::Status::unavailable("no worker endpoint available"));
+        };
+
+        endpoint.requests.fetch_add(1, Ordering::Relaxed);
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _guard = InFlightGuard(&endpoint);
+
+        let result = endpoint.client.handle_stream(request).await;
+        endpoint.record_result(!matches!(&result, Err(status) if is_endpoint_failure(status)));
+        result
+    }
+
+    /// Opens an upgrade stream to the selected endpoint. The endpoint counts
+    /// it in flight until the worker answers, not for the tunnel's lifetime.
+    pub async fn upgrade<S>(
+        &self,
+        frames: S,
+    ) -> Result<tonic::Response<tonic::Streaming<UpgradeFrame>>, tonic::Status>
+    where
+        S: Stream<Item = UpgradeFrame> + Send + 'static,
+    {
+        let Some(endpoint) = self.pick() else {
+            return Err(tonic::Status::unavailable("no worker endpoint available"));
+        };
+
+        endpoint.requests.fetch_add(1, Ordering::Relaxed);
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _guard = InFlightGuard(&endpoint);
+
+        let result = endpoint.client.upgrade(frames).await;
+        endpoint.record_result(!matches!(&result, Err(status) if is_endpoint_failure(status)));
+        result
+    }
+
+    /// Opens a WebSocket stream to the selected endpoint, counted like
+    /// [`WorkerPool::upgrade`].
+    pub async fn web_socket<S>(
+        &self,
+        frames: S,
+    ) -> Result<tonic::Response<tonic::Streaming<WebSocketFrame>>, tonic::Status>
+    where
+        S: Stream<Item = WebSocketFrame> + Send + 'static,
+    {
+        let Some(endpoint) = self.pick() else {
+            return Err(tonic::Status::unavailable("no worker endpoint available"));
+        };
+
+        endpoint.requests.fetch_add(1, Ordering::Relaxed);
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _guard = InFlightGuard(&endpoint);
+
+        let result = endpoint.client.web_socket(frames).await;
+        endpoint.record_result(!matches!(&result, Err(status) if is_endpoint_failure(status)));
+        result
+    }
+
+    /// Polls the health service of every endpoint until the process exits.
+    pub async fn run_health_checks(&self, config: HealthCheckConfig) {
+        let interval = Duration::from_millis(config.interval_ms);
+        let timeout = Duration::from_millis(config.timeout_ms);
+        loop {
+            tokio::time::sleep(interval).await;
+            let endpoints: Vec<Arc<WorkerEndpoint>> = self.endpoints.read().unwrap().clone();
+            futures::future::join_all(
+                endpoints
+                    .iter()
+                    .map(|endpoint| check_health(endpoint, timeout)),
+            )
+            .await;
+        }
+    }
+}
+
+async fn check_health(endpoint: &WorkerEndpoint, timeout: Duration) {
+    let mut client = HealthClient::new(endpoint.channel.clone());
+    let request = HealthCheckRequest {
+        service: String::new(),
+    };
+    let serving = match tokio::time::timeout(timeout, client.check(request)).await {
+        Ok(Ok(response)) => response.into_inner().status() == ServingStatus::Serving,
+        // Workers without the health service are judged on their responses
+        Ok(Err(status)) if status.code() == tonic::Code::Unimplemented => true,
+        // Unreachable endpoints are caught by the request failure count
+        Ok(Err(_)) | Err(_) => return,
+    };
+
+    let was_serving = !endpoint.not_serving.swap(!serving, Ordering::Relaxed);
+    if was_serving != serving {
+        println!(
+            "worker endpoint {} is {}",
+            endpoint.uri,
+            if serving { "serving" } else { "not serving" }
+        );
+    }
+}
+
+fn unix_channel(path: PathBuf) -> Channel {
+    // The URI is only used for the :authority of requests, the connector
+    // ignores it and dials the socket.
+    tonic::transport::Endpoint::from_static("http://localhost").connect_with_connector_lazy(
+        tower::service_fn(move |_: Uri| {
+            let path = path.clone();
+            async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(path).await?)) }
+        }),
+    )
+}
+
+fn load(endpoint: &WorkerEndpoint) -> f64 {
+    let in_flight = endpoint.in_flight.load(Ordering::Relaxed) as f64;
+    let weight = endpoint.weight.load(Ordering::Relaxed) as f64;
+    (in_flight + 1.0) / weight
+}
+
+// Errors that tell something about the endpoint rather than the request.
+fn is_endpoint_failure(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::Unknown | tonic::Code::Internal
+    )
+}
@@ -0,0 +1,292 @@
+use std::net::IpAddr;
+
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+use crate::config::ForwardedConfig;
+use crate::connection::Address;
+
+// Hop-by-hop headers (RFC 9110 section 7.6.1), only meaningful for a single
+// connection and never forwarded by a proxy.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Removes hop-by-hop headers, including every header listed as a
+/// connection option in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let connection_options: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|option| HeaderName::from_bytes(option.trim().as_bytes()).ok())
+        .collect();
+
+    for name in connection_options {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+/// Adds `Forwarded`, `X-Forwarded-For`, `X-Forwarded-Proto` and
+/// `X-Forwarded-Host` describing the client connection.
+///
+/// Values sent by a trusted proxy are kept and extended; values sent by any
+/// other peer are discarded, since the client could have forged them.
+/// Peers on Unix domain sockets have no address and appear as `unknown`.
+pub fn add_forwarded(
+    headers: &mut HeaderMap,
+    peer_addr: &Address,
+    scheme: &str,
+    host: Option<&str>,
+    config: &ForwardedConfig,
+) {
+    let client_ip = peer_addr.ip().map(|ip| ip.to_canonical());
+    let trusted = match client_ip {
+        Some(ip) => config.is_trusted(ip),
+        None => config.trust_unix_peers,
+    };
+    if !trusted {
+        headers.remove(header::FORWARDED);
+        headers.remove(&X_FORWARDED_FOR);
+        headers.remove(&X_FORWARDED_PROTO);
+        headers.remove(&X_FORWARDED_HOST);
+    }
+
+    // Forwarded: for=...;host=...;proto=...
+    let mut element = format!(
+        "for={}",
+        client_ip.map_or("unknown".to_string(), forwarded_node)
+    );
+    if let Some(host) = host {
+        element.push_str(";host=");
+        element.push_str(&forwarded_value(host));
+    }
+    element.push_str(";proto=");
+    element.push_str(scheme);
+    append_list(headers, header::FORWARDED, &element);
+
+    // X-Forwarded-For: client, proxy1, proxy2
+    let client = client_ip.map_or("unknown".to_string(), |ip| ip.to_string());
+    append_list(headers, X_FORWARDED_FOR.clone(), &client);
+
+    // X-Forwarded-Proto and X-Forwarded-Host describe the original request,
+    // so a trusted proxy's value takes precedence over ours.
+    if !headers.contains_key(&X_FORWARDED_PROTO) {
+        if let Ok(value) = HeaderValue::from_str(scheme) {
+            headers.insert(X_FORWARDED_PROTO.clone(), value);
+        }
+    }
+    if !headers.contains_key(&X_FORWARDED_HOST) {
+        if let Some(value) = host.and_then(|host| HeaderValue::from_str(host).ok()) {
+            headers.insert(X_FORWARDED_HOST.clone(), value);
+        }
+    }
+}
+
+// Appends an element to a comma separated list header, merging any
+// existing values into a single field.
+fn append_list(headers: &mut HeaderMap, name: HeaderName, element: &str) {
+    let mut list: Vec<String> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .collect();
+    list.push(element.to_string());
+
+    if let Ok(value) = HeaderValue::from_str(&list.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+// IPv6 nodes must be bracketed and quoted (RFC 7239 section 6).
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{}]\"", v6),
+    }
+}
+
+// Values that are not a plain token must be sent as a quoted string.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn config() -> ForwardedConfig {
+        ForwardedConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            trust_unix_peers: false,
+        }
+    }
+
+    fn inet(addr: &str) -> Address {
+        Address::Inet(addr.parse().unwrap())
+    }
+
+    // Headers sent by a client claiming to be someone else
+    fn spoofed() -> HeaderMap {
+        headers(&[
+            ("forwarded", "for=203.0.113.9;proto=https"),
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "admin.example.com"),
+        ])
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = headers(&[
+            ("connection", "keep-alive, X-Secret , close"),
+            ("keep-alive", "timeout=5"),
+            ("x-secret", "token"),
+            ("transfer-encoding", "chunked"),
+            ("te", "trailers"),
+            ("upgrade", "websocket"),
+            ("proxy-authorization", "Basic YTpi"),
+            ("content-type", "text/plain"),
+        ]);
+        strip_hop_by_hop(&mut headers);
+
+        let names: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+        assert_eq!(names, ["content-type"]);
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_spoof() {
+        let mut headers = spoofed();
+        add_forwarded(
+            &mut headers,
+            &inet("192.0.2.1:40000"),
+            "http",
+            Some("example.com"),
+            &config(),
+        );
+
+        assert_eq!(
+            headers[header::FORWARDED],
+            "for=192.0.2.1;host=example.com;proto=http"
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "192.0.2.1");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[&X_FORWARDED_HOST], "example.com");
+    }
+
+    #[test]
+    fn trusted_proxies_are_extended() {
+        let mut headers = spoofed();
+        headers.append(&X_FORWARDED_FOR, HeaderValue::from_static("10.1.2.3"));
+        add_forwarded(
+            &mut headers,
+            &inet("10.0.0.5:40000"),
+            "http",
+            Some("internal"),
+            &config(),
+        );
+
+        assert_eq!(
+            headers[header::FORWARDED],
+            "for=203.0.113.9;proto=https, for=10.0.0.5;host=internal;proto=http"
+        );
+        assert_eq!(headers.get_all(&X_FORWARDED_FOR).iter().count(), 1);
+        assert_eq!(headers[&X_FORWARDED_FOR], "203.0.113.9, 10.1.2.3, 10.0.0.5");
+        // The original request's values win over the proxy connection's
+        assert_eq!(headers[&X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[&X_FORWARDED_HOST], "admin.example.com");
+    }
+
+    #[test]
+    fn ipv4_mapped_peers() {
+        let mut headers = spoofed();
+        add_forwarded(
+            &mut headers,
+            &inet("[::ffff:10.0.0.5]:40000"),
+            "http",
+            None,
+            &config(),
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "203.0.113.9, 10.0.0.5");
+    }
+
+    #[test]
+    fn unix_peers() {
+        let peer = Address::Unix(None);
+
+        let mut headers = spoofed();
+        add_forwarded(&mut headers, &peer, "http", None, &config());
+        assert_eq!(headers[header::FORWARDED], "for=unknown;proto=http");
+        assert_eq!(headers[&X_FORWARDED_FOR], "unknown");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "http");
+
+        let trusting = ForwardedConfig {
+            trust_unix_peers: true,
+            ..config()
+        };
+        let mut headers = spoofed();
+        add_forwarded(&mut headers, &peer, "http", None, &trusting);
+        assert_eq!(
+            headers[header::FORWARDED],
+            "for=203.0.113.9;proto=https, for=unknown;proto=http"
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "203.0.113.9, unknown");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "https");
+    }
+
+    #[test]
+    fn forwarded_quoting() {
+        let mut headers = HeaderMap::new();
+        add_forwarded(
+            &mut headers,
+            &inet("[2001:db8::1]:40000"),
+            "https",
+            Some("example.com:8443"),
+            &config(),
+        );
+        assert_eq!(
+            headers[header::FORWARDED],
+            "for=\"[2001:db8::1]\";host=\"example.com:8443\";proto=https"
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "2001:db8::1");
+
+        assert_eq!(forwarded_value("example.com"), "example.com");
+        assert_eq!(forwarded_value(""), "\"\"");
+        assert_eq!(forwarded_value("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+}
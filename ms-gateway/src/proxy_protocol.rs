@@ -0,0 +1,358 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::listener::Io;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// A v1 line with the longest TCP6 addresses fits in 107 bytes
+const V1_MAX_LEN: usize = 107;
+// Large enough for the address block of any family plus TLVs
+const V2_MAX_LEN: usize = 16 + 1024;
+
+/// Addresses carried by a PROXY protocol header.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxiedAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Reads the PROXY protocol header at the start of `stream`.
+///
+/// Returns the stream, replaying any bytes read past the header, and the
+/// addresses of the proxied connection. Headers without addresses (v1
+/// `UNKNOWN`, v2 `LOCAL` or non-IP families) give `None`.
+pub async fn read_header(
+    mut stream: Box<dyn Io>,
+) -> io::Result<(Box<dyn Io>, Option<ProxiedAddrs>)> {
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        if let Some((len, addrs)) = parse(&buf)? {
+            let rest = buf.split_off(len);
+            let stream: Box<dyn Io> = if rest.is_empty() {
+                stream
+            } else {
+                Box::new(Prefixed {
+                    prefix: rest,
+                    position: 0,
+                    inner: stream,
+                })
+            };
+            return Ok((stream, addrs));
+        }
+
+        let mut chunk = [0u8; 256];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(invalid("connection closed before the PROXY header"));
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+}
+
+// Parses a complete header, or returns None when more bytes are needed.
+fn parse(buf: &[u8]) -> io::Result<Option<(usize, Option<ProxiedAddrs>)>> {
+    let prefix_len = buf.len().min(V2_SIGNATURE.len());
+    if buf[..prefix_len] == V2_SIGNATURE[..prefix_len] {
+        return if buf.len() < 16 {
+            Ok(None)
+        } else {
+            parse_v2(buf)
+        };
+    }
+
+    let prefix_len = buf.len().min(6);
+    if buf[..prefix_len] == b"PROXY "[..prefix_len] {
+        return if buf.len() < 6 {
+            Ok(None)
+        } else {
+            parse_v1(buf)
+        };
+    }
+    Err(invalid("missing PROXY protocol header"))
+}
+
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(usize, Option<ProxiedAddrs>)>> {
+    let Some(end) = buf.windows(2).position(|window| window == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        return Ok(None);
+    };
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("invalid PROXY v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addrs = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let parse_addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| invalid("invalid PROXY v1 address"))?;
+                let port: u16 = port.parse().map_err(|_| invalid("invalid PROXY v1 port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Some(ProxiedAddrs {
+                source: parse_addr(source, source_port)?,
+                destination: parse_addr(destination, destination_port)?,
+            })
+        }
+        _ => return Err(invalid("invalid PROXY v1 header")),
+    };
+    Ok(Some((end + 2, addrs)))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(usize, Option<ProxiedAddrs>)>> {
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if len > V2_MAX_LEN {
+        return Err(invalid("PROXY v2 header too long"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    // LOCAL: health checks of the balancer itself, keep the real peer
+    if version_command & 0x0f == 0 {
+        return Ok(Some((len, None)));
+    }
+    if version_command & 0x0f != 1 {
+        return Err(invalid("unsupported PROXY v2 command"));
+    }
+
+    let block = &buf[16..len];
+    let port = |offset: usize| u16::from_be_bytes([block[offset], block[offset + 1]]);
+    let addrs = match family >> 4 {
+        // AF_INET
+        1 if block.len() >= 12 => {
+            let source = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let destination = Ipv4Addr::new(block[4], block[5], block[6], block[7]);
+            Some(ProxiedAddrs {
+                source: SocketAddr::new(source.into(), port(8)),
+                destination: SocketAddr::new(destination.into(), port(10)),
+            })
+        }
+        // AF_INET6
+        2 if block.len() >= 36 => {
+            let source: [u8; 16] = block[0..16].try_into().unwrap();
+            let destination: [u8; 16] = block[16..32].try_into().unwrap();
+            Some(ProxiedAddrs {
+                source: SocketAddr::new(Ipv6Addr::from(source).into(), port(32)),
+                destination: SocketAddr::new(Ipv6Addr::from(destination).into(), port(34)),
+            })
+        }
+        1 | 2 => return Err(invalid("truncated PROXY v2 addresses")),
+        // AF_UNSPEC, AF_UNIX
+        _ => None,
+    };
+    Ok(Some((len, addrs)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Stream replaying bytes read past the header before reading on.
+struct Prefixed {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: Box<dyn Io>,
+}
+
+impl AsyncRead for Prefixed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let len = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            self.position += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Prefixed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, block: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+        header.extend_from_slice(block);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET /";
+        let (len, addrs) = parse(buf).unwrap().unwrap();
+        assert_eq!(len, buf.len() - 5);
+        let addrs = addrs.unwrap();
+        assert_eq!(addrs.source, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(addrs.destination, "198.51.100.2:443".parse().unwrap());
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n";
+        let addrs = parse(buf).unwrap().unwrap().1.unwrap();
+        assert_eq!(addrs.source, "[2001:db8::1]:4000".parse().unwrap());
+        assert_eq!(addrs.destination, "[2001:db8::2]:80".parse().unwrap());
+    }
+
+    #[test]
+    fn v1_unknown_has_no_addresses() {
+        let buf = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        let (len, addrs) = parse(buf).unwrap().unwrap();
+        assert_eq!(len, buf.len());
+        assert!(addrs.is_none());
+    }
+
+    #[test]
+    fn v1_truncated_needs_more() {
+        assert!(parse(b"PRO").unwrap().is_none());
+        assert!(parse(b"PROXY TCP4 192.0.2.1").unwrap().is_none());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 1 2\r")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn v1_oversized() {
+        let mut buf = b"PROXY TCP4 ".to_vec();
+        buf.resize(V1_MAX_LEN, b'1');
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn v1_invalid() {
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 70000 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.300 198.51.100.2 1 443\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.0.2.1 198.51.100.2 1 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 1\r\n").is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn v2_inet() {
+        let block = [192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let mut buf = v2(1, 0x11, &block);
+        let header_len = buf.len();
+        buf.extend_from_slice(b"GET /");
+        let (len, addrs) = parse(&buf).unwrap().unwrap();
+        assert_eq!(len, header_len);
+        let addrs = addrs.unwrap();
+        assert_eq!(addrs.source, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(addrs.destination, "198.51.100.2:443".parse().unwrap());
+    }
+
+    #[test]
+    fn v2_inet6() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut block = source.octets().to_vec();
+        block.extend_from_slice(&destination.octets());
+        block.extend_from_slice(&[0x0f, 0xa0, 0x00, 0x50]);
+        let addrs = parse(&v2(1, 0x21, &block)).unwrap().unwrap().1.unwrap();
+        assert_eq!(addrs.source, "[2001:db8::1]:4000".parse().unwrap());
+        assert_eq!(addrs.destination, "[2001:db8::2]:80".parse().unwrap());
+    }
+
+    #[test]
+    fn v2_without_addresses() {
+        // LOCAL, with whatever block it carries
+        let buf = v2(0, 0x11, &[0; 12]);
+        let (len, addrs) = parse(&buf).unwrap().unwrap();
+        assert_eq!(len, buf.len());
+        assert!(addrs.is_none());
+        // AF_UNIX and AF_UNSPEC
+        assert!(parse(&v2(1, 0x31, &[0; 216])).unwrap().unwrap().1.is_none());
+        assert!(parse(&v2(1, 0x00, &[])).unwrap().unwrap().1.is_none());
+    }
+
+    #[test]
+    fn v2_truncated_needs_more() {
+        let buf = v2(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 2, 0, 1, 0, 2]);
+        for len in 1..buf.len() {
+            assert!(parse(&buf[..len]).unwrap().is_none(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn v2_invalid() {
+        // Address block shorter than the family needs
+        assert!(parse(&v2(1, 0x11, &[192, 0, 2, 1])).is_err());
+        assert!(parse(&v2(1, 0x21, &[0; 12])).is_err());
+        // Length beyond the limit, refused before it is read
+        let mut oversized = v2(1, 0x11, &[]);
+        oversized[14..16].copy_from_slice(&(V2_MAX_LEN as u16).to_be_bytes());
+        assert!(parse(&oversized).is_err());
+        // Version 1 in a v2 header, unknown command
+        let mut version = v2(1, 0x11, &[0; 12]);
+        version[12] = 0x11;
+        assert!(parse(&version).is_err());
+        assert!(parse(&v2(2, 0x11, &[0; 12])).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_header_replays_the_rest() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::io::AsyncWriteExt::write_all(
+            &mut client,
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET / HTTP/1.1\r\n",
+        )
+        .await
+        .unwrap();
+        drop(client);
+
+        let (mut stream, addrs) = read_header(Box::new(server)).await.unwrap();
+        assert_eq!(addrs.unwrap().source, "192.0.2.1:56324".parse().unwrap());
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+}
@@ -0,0 +1,230 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{Config, RuntimeMode};
+use crate::handoff::{self, InheritedSocket, ReadyPipe};
+use crate::shutdown::Signals;
+
+// Set on the processes started in `processes` mode, to their index
+const INSTANCE_ENV: &str = "MS_EXECUTOR_INSTANCE";
+
+/// One of the executors running in this process or in sibling processes,
+/// each with its own listeners, worker channels and state.
+#[derive(Debug)]
+pub struct Instance {
+    pub index: usize,
+    pub count: usize,
+    /// Listening sockets passed by systemd or a previous process.
+    pub inherited: Vec<InheritedSocket>,
+    pub(crate) readiness: Option<Arc<Readiness>>,
+}
+
+impl Instance {
+    /// Whether other instances accept on the same addresses, through
+    /// `SO_REUSEPORT`.
+    pub fn is_shared(&self) -> bool {
+        self.count > 1
+    }
+
+    /// The instance serving Unix sockets, which cannot be bound twice.
+    pub fn is_primary(&self) -> bool {
+        self.index == 0
+    }
+}
+
+// Tells the process that started this one, if any, that it is serving
+// once every instance has bound its listeners
+#[derive(Debug)]
+pub(crate) struct Readiness {
+    pipe: Mutex<Option<ReadyPipe>>,
+    pending: AtomicUsize,
+}
+
+impl Readiness {
+    fn new(pipe: Option<ReadyPipe>, instances: usize) -> Option<Arc<Readiness>> {
+        pipe.map(|pipe| {
+            Arc::new(Readiness {
+                pipe: Mutex::new(Some(pipe)),
+                pending: AtomicUsize::new(instances),
+            })
+        })
+    }
+
+    pub(crate) fn instance_ready(&self) {
+        if self.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            if let Some(pipe) = self.pipe.lock().unwrap().take() {
+                pipe.notify();
+            }
+        }
+    }
+}
+
+/// Runs `run` as selected by `config.runtime`, until every instance ends.
+pub fn start<F, Fut>(config: Config, run: F)
+where
+    F: Fn(Config, Instance) -> Fut + Send + Clone + 'static,
+    Fut: Future<Output = ()>,
+{
+    let count = match config.runtime.instances {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        count => count,
+    };
+
+    match config.runtime.mode {
+        RuntimeMode::Single => {
+            let instance = Instance {
+                index: 0,
+                count: 1,
+                inherited: handoff::inherited_sockets(),
+                readiness: Readiness::new(handoff::ready_pipe(), 1),
+            };
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build the tokio runtime");
+            runtime.block_on(run(config, instance));
+        }
+        RuntimeMode::ThreadPerCore => {
+            let inherited = handoff::inherited_sockets();
+            let readiness = Readiness::new(handoff::ready_pipe(), count);
+            let threads: Vec<_> = (0..count)
+                .map(|index| {
+                    let config = config.clone();
+                    let run = run.clone();
+                    let readiness = readiness.clone();
+                    // Each thread accepts on its own copy of the fds
+                    let inherited = inherited
+                        .iter()
+                        .filter_map(|socket| socket.try_clone().ok())
+                        .collect();
+                    std::thread::Builder::new()
+                        .name(format!("executor-{}", index))
+                        .spawn(move || {
+                            pin_to_core(index);
+                            let instance = Instance {
+                                index,
+                                count,
+                                inherited,
+                                readiness,
+                            };
+                            let runtime = tokio::runtime::Builder::new_current_thread()
+                                .enable_all()
+                                .build()
+                                .expect("failed to build the tokio runtime");
+                            runtime.block_on(run(config, instance));
+                        })
+                        .expect("failed to start an executor thread")
+                })
+                .collect();
+            drop(inherited);
+            for thread in threads {
+                let _ = thread.join();
+            }
+        }
+        RuntimeMode::Processes => match std::env::var(INSTANCE_ENV) {
+            Ok(index) => {
+                let instance = Instance {
+                    index: index.parse().unwrap_or_default(),
+                    count,
+                    inherited: handoff::inherited_sockets(),
+                    readiness: None,
+                };
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("failed to build the tokio runtime");
+                runtime.block_on(run(config, instance));
+            }
+            Err(_) => {
+                let ready_pipe = handoff::ready_pipe();
+                handoff::pass_to_children();
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("failed to build the tokio runtime");
+                runtime.block_on(supervise(count, ready_pipe));
+            }
+        },
+    }
+}
+
+// Starts one executor process per instance and forwards termination
+// signals to them until they have all exited.
+async fn supervise(count: usize, ready_pipe: Option<ReadyPipe>) {
+    let mut signals = match Signals::new() {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("signal handler error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut args = std::env::args_os();
+    let program = args.next().expect("no program name");
+    let args: Vec<_> = args.collect();
+
+    let mut children = Vec::new();
+    for index in 0..count {
+        let child = tokio::process::Command::new(&program)
+            .args(&args)
+            .env(INSTANCE_ENV, index.to_string())
+            // Signals from the terminal reach the supervisor only
+            .process_group(0)
+            .spawn();
+        match child {
+            Ok(child) => children.push(child),
+            Err(e) => eprintln!("cannot start executor process {}: {}", index, e),
+        }
+    }
+    println!("supervising {} executor processes", children.len());
+    // The children cannot report back, they are counted as ready once
+    // started
+    if let Some(ready_pipe) = ready_pipe {
+        ready_pipe.notify();
+    }
+
+    let pids: Vec<i32> = children
+        .iter()
+        .filter_map(|child| child.id())
+        .map(|pid| pid as i32)
+        .collect();
+    let wait_all = futures::future::join_all(children.iter_mut().map(|child| child.wait()));
+    let mut wait_all = std::pin::pin!(wait_all);
+
+    loop {
+        tokio::select! {
+            statuses = wait_all.as_mut() => {
+                for status in statuses.into_iter().flatten() {
+                    if !status.success() {
+                        eprintln!("executor process exited with {}", status);
+                    }
+                }
+                return;
+            }
+            signal = signals.recv() => {
+                println!("{} received, forwarding to executor processes", signal.name());
+                for pid in &pids {
+                    // SAFETY: kill has no memory safety requirements
+                    unsafe { libc::kill(*pid, signal.number()) };
+                }
+            }
+        }
+    }
+}
+
+fn pin_to_core(index: usize) {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    // SAFETY: the cpu_set_t is zeroed and only used in this call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(index % cores, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == -1 {
+            eprintln!(
+                "cannot pin executor thread {}: {}",
+                index,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
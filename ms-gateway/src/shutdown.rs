@@ -0,0 +1,52 @@
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Termination signals the executor shuts down on.
+pub struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+    quit: Signal,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Termination {
+    Terminate,
+    Interrupt,
+    Quit,
+}
+
+impl Termination {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Termination::Terminate => "SIGTERM",
+            Termination::Interrupt => "SIGINT",
+            Termination::Quit => "SIGQUIT",
+        }
+    }
+
+    pub fn number(&self) -> i32 {
+        match self {
+            Termination::Terminate => libc::SIGTERM,
+            Termination::Interrupt => libc::SIGINT,
+            Termination::Quit => libc::SIGQUIT,
+        }
+    }
+}
+
+impl Signals {
+    pub fn new() -> std::io::Result<Signals> {
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            quit: signal(SignalKind::quit())?,
+        })
+    }
+
+    /// Waits for the next signal.
+    pub async fn recv(&mut self) -> Termination {
+        tokio::select! {
+            _ = self.terminate.recv() => Termination::Terminate,
+            _ = self.interrupt.recv() => Termination::Interrupt,
+            _ = self.quit.recv() => Termination::Quit,
+        }
+    }
+}
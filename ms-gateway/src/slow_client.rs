@@ -0,0 +1,399 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use hyper::body::{Body, Bytes, Frame, Incoming};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::config::SlowClientConfig;
+use crate::connection::ConnectionStats;
+use crate::millis;
+
+/// Error reading a request body.
+#[derive(Debug)]
+pub enum BodyError {
+    Hyper(hyper::Error),
+    /// The client sent the body too slowly, with the limit it exceeded.
+    TooSlow(&'static str),
+    /// The body is larger than the limit set with [`RequestBody::limit`].
+    TooLarge,
+}
+
+/// Whether a connection error is hyper timing out on the client, for the
+/// request headers or an HTTP/2 ping.
+pub fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<hyper::Error>()
+        .is_some_and(hyper::Error::is_timeout)
+}
+
+// Time at which a transfer started at `since` falls below `min_rate` bytes
+// per second, never before the grace period ends
+fn rate_deadline(since: Instant, transferred: u64, min_rate: u64, grace: Duration) -> Instant {
+    let needed = Duration::from_secs_f64(transferred as f64 / min_rate as f64);
+    since + needed.max(grace)
+}
+
+/// Request body failing with [`BodyError::TooSlow`] when no part arrives
+/// within `body_read_timeout_ms`, or when it averages less than
+/// `min_upload_rate` after the grace period.
+pub struct RequestBody {
+    inner: Incoming,
+    started: Instant,
+    received: u64,
+    max_size: Option<u64>,
+    read_timeout: Option<Duration>,
+    min_rate: u64,
+    grace: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl RequestBody {
+    pub fn new(inner: Incoming, config: &SlowClientConfig) -> RequestBody {
+        let now = Instant::now();
+        let mut body = RequestBody {
+            inner,
+            started: now,
+            received: 0,
+            max_size: None,
+            read_timeout: millis(config.body_read_timeout_ms),
+            min_rate: config.min_upload_rate,
+            grace: Duration::from_millis(config.min_rate_grace_ms),
+            deadline: Box::pin(tokio::time::sleep_until(now)),
+        };
+        body.reset_deadline(now);
+        body
+    }
+
+    /// Fails with [`BodyError::TooLarge`] as soon as more than `max_size`
+    /// bytes arrive, without reading the rest.
+    pub fn limit(mut self, max_size: u64) -> RequestBody {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    // The deadline is the earliest of the read timeout and the time the
+    // upload rate would fall below the minimum
+    fn reset_deadline(&mut self, now: Instant) {
+        let timeout = self
+            .read_timeout
+            .map(|timeout| (now + timeout, "body read timeout"));
+        let rate = (self.min_rate > 0).then(|| {
+            let at = rate_deadline(self.started, self.received, self.min_rate, self.grace);
+            (at, "upload rate below the minimum")
+        });
+        if let Some((at, _)) = timeout.into_iter().chain(rate).min_by_key(|(at, _)| *at) {
+            self.deadline.as_mut().reset(at);
+        }
+    }
+
+    fn exceeded(&self) -> Option<&'static str> {
+        if self.read_timeout.is_none() && self.min_rate == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        if self.min_rate > 0
+            && now >= rate_deadline(self.started, self.received, self.min_rate, self.grace)
+        {
+            return Some("upload rate below the minimum");
+        }
+        Some("body read timeout")
+    }
+}
+
+impl Body for RequestBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.received += data.len() as u64;
+                }
+                if self
+                    .max_size
+                    .is_some_and(|max_size| self.received > max_size)
+                {
+                    return Poll::Ready(Some(Err(BodyError::TooLarge)));
+                }
+                self.reset_deadline(Instant::now());
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(BodyError::Hyper(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                if self.read_timeout.is_none() && self.min_rate == 0 {
+                    return Poll::Pending;
+                }
+                ready!(self.deadline.as_mut().poll(cx));
+                Poll::Ready(
+                    self.exceeded()
+                        .map(|reason| Err(BodyError::TooSlow(reason))),
+                )
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Writes blocked by a client not reading, from the first one that could
+// not complete straight away
+struct Blocked {
+    since: Instant,
+    progress: Instant,
+    written: u64,
+}
+
+/// Client connection failing writes with `TimedOut` once they stay
+/// blocked for `write_timeout_ms`, or when the client reads at less than
+/// `min_download_rate` while writes are blocked. Offending connections are
+/// counted as slow clients.
+pub struct SlowClientIo<T> {
+    inner: T,
+    write_timeout: Option<Duration>,
+    min_rate: u64,
+    grace: Duration,
+    blocked: Option<Blocked>,
+    // Whether the last write had to wait for the client
+    waited: bool,
+    deadline: Pin<Box<Sleep>>,
+    connections: Arc<ConnectionStats>,
+}
+
+impl<T> SlowClientIo<T> {
+    pub fn new(
+        inner: T,
+        config: &SlowClientConfig,
+        connections: Arc<ConnectionStats>,
+    ) -> SlowClientIo<T> {
+        SlowClientIo {
+            inner,
+            write_timeout: millis(config.write_timeout_ms),
+            min_rate: config.min_download_rate,
+            grace: Duration::from_millis(config.min_rate_grace_ms),
+            blocked: None,
+            waited: false,
+            deadline: Box::pin(tokio::time::sleep_until(Instant::now())),
+            connections,
+        }
+    }
+
+    fn track(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        if self.write_timeout.is_none() && self.min_rate == 0 {
+            return poll;
+        }
+        match poll {
+            Poll::Ready(Ok(written)) => {
+                match &mut self.blocked {
+                    // The client keeps up again
+                    Some(_) if !self.waited => self.blocked = None,
+                    Some(blocked) => {
+                        blocked.written += written as u64;
+                        blocked.progress = Instant::now();
+                    }
+                    None => {}
+                }
+                self.waited = false;
+                Poll::Ready(Ok(written))
+            }
+            Poll::Pending => {
+                self.waited = true;
+                match self.check(cx) {
+                    Some(e) => Poll::Ready(Err(e)),
+                    None => Poll::Pending,
+                }
+            }
+            poll => poll,
+        }
+    }
+
+    fn check(&mut self, cx: &mut Context<'_>) -> Option<io::Error> {
+        let now = Instant::now();
+        let blocked = self.blocked.get_or_insert(Blocked {
+            since: now,
+            progress: now,
+            written: 0,
+        });
+
+        let timeout = self
+            .write_timeout
+            .map(|timeout| (blocked.progress + timeout, "write timeout"));
+        let rate = (self.min_rate > 0).then(|| {
+            let at = rate_deadline(blocked.since, blocked.written, self.min_rate, self.grace);
+            (at, "download rate below the minimum")
+        });
+        let (at, reason) = timeout.into_iter().chain(rate).min_by_key(|(at, _)| *at)?;
+        if self.deadline.deadline() != at {
+            self.deadline.as_mut().reset(at);
+        }
+        if self.deadline.as_mut().poll(cx).is_pending() {
+            return None;
+        }
+
+        self.connections
+            .slow_clients
+            .fetch_add(1, Ordering::Relaxed);
+        Some(io::Error::new(io::ErrorKind::TimedOut, reason))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for SlowClientIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for SlowClientIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.track(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        if poll.is_pending() && (self.write_timeout.is_some() || self.min_rate > 0) {
+            if let Some(e) = self.check(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        poll
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn rate_deadlines() {
+        let since = Instant::now();
+        let secs = Duration::from_secs;
+
+        // Nothing transferred yet: the grace period alone
+        assert_eq!(rate_deadline(since, 0, 1000, secs(5)), since + secs(5));
+        assert_eq!(rate_deadline(since, 2000, 1000, secs(5)), since + secs(5));
+        // Past the grace period, the time the bytes buy at the minimum rate
+        assert_eq!(
+            rate_deadline(since, 10_000, 1000, secs(5)),
+            since + secs(10)
+        );
+        assert_eq!(
+            rate_deadline(since, 1500, 1000, Duration::ZERO),
+            since + Duration::from_millis(1500)
+        );
+    }
+
+    fn config(write_timeout_ms: u64, min_download_rate: u64) -> SlowClientConfig {
+        SlowClientConfig {
+            write_timeout_ms,
+            min_download_rate,
+            min_rate_grace_ms: 0,
+            ..Default::default()
+        }
+    }
+
+    // Writes to a client that does not read until the write fails
+    async fn write_until_blocked(io: &mut SlowClientIo<tokio::io::DuplexStream>) -> io::Error {
+        let data = [0u8; 1024];
+        let write = async {
+            loop {
+                if let Err(e) = io.write_all(&data).await {
+                    return e;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), write)
+            .await
+            .expect("the blocked write did not fail")
+    }
+
+    #[tokio::test]
+    async fn write_timeout() {
+        let stats = Arc::new(ConnectionStats::default());
+        let (client, _peer) = tokio::io::duplex(4096);
+        let mut io = SlowClientIo::new(client, &config(50, 0), stats.clone());
+
+        let e = write_until_blocked(&mut io).await;
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(e.to_string(), "write timeout");
+        assert_eq!(stats.slow_clients.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn download_rate() {
+        let stats = Arc::new(ConnectionStats::default());
+        let (client, _peer) = tokio::io::duplex(4096);
+        let mut io = SlowClientIo::new(client, &config(0, 1_000_000), stats.clone());
+
+        let e = write_until_blocked(&mut io).await;
+        assert_eq!(e.to_string(), "download rate below the minimum");
+        assert_eq!(stats.slow_clients.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn reading_client_is_not_slow() {
+        let stats = Arc::new(ConnectionStats::default());
+        let (client, mut peer) = tokio::io::duplex(4096);
+        let mut io = SlowClientIo::new(client, &config(200, 0), stats.clone());
+
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut total = 0;
+            while total < 64 * 1024 {
+                total += peer.read(&mut buf).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        for _ in 0..64 {
+            io.write_all(&[0u8; 1024]).await.unwrap();
+        }
+        reader.await.unwrap();
+        assert_eq!(stats.slow_clients.load(Ordering::Relaxed), 0);
+    }
+}
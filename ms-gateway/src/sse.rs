@@ -0,0 +1,228 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::Response;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tower::BoxError;
+
+// httpgrpc - protos
+use protos::httpgrpc::{HttpRequest, HttpResponse};
+
+use crate::body::ResponseBody;
+use crate::config::SseConfig;
+use crate::connection::{ConnectionStats, RequestGuard};
+use crate::convert::{to_http_headers, to_http_status};
+use crate::pool::WorkerPool;
+use crate::proxy_headers;
+
+pub const EVENT_STREAM: &str = "text/event-stream";
+// A comment line, ignored by EventSource clients
+const HEARTBEAT: &[u8] = b":\n\n";
+
+/// Whether a request goes over the streaming RPC: the client asks for an
+/// event stream in `Accept` on a path configured to serve them.
+pub fn wants_stream(config: &SseConfig, path: &str, headers: &HeaderMap) -> bool {
+    let path_matches = config.path_prefixes.is_empty()
+        || config
+            .path_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()));
+    config.enabled && path_matches && accepts_event_stream(headers)
+}
+
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(is_event_stream)
+}
+
+// Whether a media type, parameters included, is text/event-stream
+fn is_event_stream(media_type: &str) -> bool {
+    media_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case(EVENT_STREAM)
+}
+
+/// Response of the `HandleStream` RPC.
+pub enum Streamed {
+    /// An event stream, written to the client as the worker produces it.
+    Events(Response<ResponseBody>),
+    /// Any other response, read whole so it goes through the regular
+    /// response handling.
+    Complete(HttpResponse),
+}
+
+/// Sends a request over the `HandleStream` RPC. A `text/event-stream`
+/// response is streamed, each event written to the client as soon as it
+/// arrives.
+///
+/// Event streams get a heartbeat comment after `heartbeat_interval_ms`
+/// without an event. The response counts as a request in flight until it
+/// ends.
+pub async fn forward(
+    pool: &WorkerPool,
+    grpc_request: HttpRequest,
+    config: &SseConfig,
+    connections: &Arc<ConnectionStats>,
+) -> Result<Streamed, tonic::Status> {
+    let mut parts = pool.handle_stream(grpc_request).await?.into_inner();
+    let Some(mut first) = parts.message().await? else {
+        return Err(tonic::Status::internal(
+            "the worker ended the response stream without a response",
+        ));
+    };
+
+    let mut headers = to_http_headers(first.headers.clone());
+    let event_stream = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_event_stream);
+    if !event_stream {
+        while let Some(part) = parts.message().await? {
+            first.body.extend(part.body);
+            first.trailers.extend(part.trailers);
+        }
+        return Ok(Streamed::Complete(first));
+    }
+
+    let status = to_http_status(first.status);
+    proxy_headers::strip_hop_by_hop(&mut headers);
+    // The length is unknown until the worker ends the stream
+    headers.remove(header::CONTENT_LENGTH);
+    headers
+        .entry(header::CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static("no-cache"));
+    // Proxies such as nginx would otherwise buffer the events
+    headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+
+    // hyper sends the headers with the first chunk, the client should not
+    // wait for the first event to see the stream open
+    let first_body = if first.body.is_empty() {
+        HEARTBEAT.to_vec()
+    } else {
+        first.body
+    };
+    let stream = PartStream {
+        first: Some(first_body),
+        parts,
+        heartbeat: heartbeat_interval(config.heartbeat_interval_ms),
+        _request_guard: connections.start_request(),
+    };
+    let body = futures::stream::unfold(stream, PartStream::next);
+
+    let mut res = Response::new(StreamBody::new(body).boxed_unsync());
+    *res.status_mut() = status;
+    *res.headers_mut() = headers;
+    Ok(Streamed::Events(res))
+}
+
+fn heartbeat_interval(interval_ms: u64) -> Option<Interval> {
+    if interval_ms == 0 {
+        return None;
+    }
+    let period = Duration::from_millis(interval_ms);
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Some(interval)
+}
+
+struct PartStream {
+    first: Option<Vec<u8>>,
+    parts: tonic::Streaming<HttpResponse>,
+    heartbeat: Option<Interval>,
+    _request_guard: RequestGuard,
+}
+
+impl PartStream {
+    async fn next(mut self) -> Option<(Result<Frame<Bytes>, BoxError>, PartStream)> {
+        if let Some(body) = self.first.take().filter(|body| !body.is_empty()) {
+            return Some((Ok(Frame::data(Bytes::from(body))), self));
+        }
+
+        loop {
+            let part = tokio::select! {
+                part = self.parts.message() => Some(part),
+                _ = tick(&mut self.heartbeat) => None,
+            };
+            let Some(part) = part else {
+                return Some((Ok(Frame::data(Bytes::from_static(HEARTBEAT))), self));
+            };
+            match part {
+                Ok(Some(part)) if part.body.is_empty() => {}
+                Ok(Some(part)) => {
+                    if let Some(interval) = &mut self.heartbeat {
+                        interval.reset();
+                    }
+                    return Some((Ok(Frame::data(Bytes::from(part.body))), self));
+                }
+                Ok(None) => return None,
+                Err(status) => {
+                    // hyper resets the stream or closes the connection, so
+                    // the client cannot take the response as complete
+                    eprintln!("grpc error: {}", status);
+                    return Some((Err(status.into()), self));
+                }
+            }
+        }
+    }
+}
+
+async fn tick(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn stream_requests() {
+        let config = SseConfig {
+            enabled: true,
+            path_prefixes: vec!["/events/".to_string()],
+            ..Default::default()
+        };
+        let events = accept("text/event-stream");
+
+        assert!(wants_stream(&config, "/events/ticks", &events));
+        assert!(wants_stream(
+            &config,
+            "/events/ticks",
+            &accept("application/json, Text/Event-Stream; q=0.9")
+        ));
+        assert!(!wants_stream(&config, "/api/users", &events));
+        assert!(!wants_stream(&config, "/events/ticks", &accept("*/*")));
+        assert!(!wants_stream(&config, "/events/ticks", &HeaderMap::new()));
+
+        let every_path = SseConfig {
+            path_prefixes: Vec::new(),
+            ..config
+        };
+        assert!(wants_stream(&every_path, "/api/users", &events));
+        assert!(!wants_stream(
+            &SseConfig::default(),
+            "/events/ticks",
+            &events
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+use std::io;
+use std::pin::pin;
+use std::sync::Arc;
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+// httpgrpc - protos
+use protos::httpgrpc::upgrade_frame::Frame;
+use protos::httpgrpc::{HttpRequest, UpgradeFrame};
+
+use crate::body::{self, ResponseBody};
+use crate::connection::{ConnectionSlot, ConnectionStats};
+use crate::convert::{to_http_headers, to_http_status};
+use crate::pool::WorkerPool;
+use crate::proxy_headers;
+
+// Frames buffered towards the worker before reading from the client pauses
+const FRAME_BUFFER: usize = 16;
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// The protocol an HTTP/1.1 request asks to switch to, from its `Upgrade`
+/// header when `Connection` lists `upgrade`.
+pub fn requested_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let upgrade_option = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("upgrade"));
+    if !upgrade_option {
+        return None;
+    }
+    headers.get(header::UPGRADE).cloned()
+}
+
+/// Sends an upgrade request, or a CONNECT request when `protocol` is
+/// `None`, to a worker over the `Upgrade` stream.
+///
+/// When the worker switches protocols (or accepts the CONNECT request with
+/// a 2xx status), the client connection is spliced
+/// onto the stream once hyper hands it over, in a task counted as a request
+/// in flight. Any other response is returned as is.
+pub async fn forward(
+    pool: &WorkerPool,
+    grpc_request: HttpRequest,
+    protocol: Option<HeaderValue>,
+    on_upgrade: OnUpgrade,
+    connections: &Arc<ConnectionStats>,
+    slot: Option<Arc<ConnectionSlot>>,
+) -> Result<Response<ResponseBody>, tonic::Status> {
+    let (sender, receiver) = mpsc::channel(FRAME_BUFFER);
+    let first = UpgradeFrame {
+        frame: Some(Frame::Request(grpc_request)),
+    };
+    // The receiver is alive and the buffer empty
+    let _ = sender.try_send(first);
+
+    let mut frames = pool
+        .upgrade(ReceiverStream::new(receiver))
+        .await?
+        .into_inner();
+    let grpc_response = match frames.message().await? {
+        Some(UpgradeFrame {
+            frame: Some(Frame::Response(grpc_response)),
+        }) => grpc_response,
+        _ => {
+            return Err(tonic::Status::internal(
+                "the worker did not start the upgrade stream with a response",
+            ))
+        }
+    };
+
+    let status = to_http_status(grpc_response.status);
+    let mut headers = to_http_headers(grpc_response.headers);
+    let worker_protocol = headers.get(header::UPGRADE).cloned();
+    proxy_headers::strip_hop_by_hop(&mut headers);
+
+    let switching = match &protocol {
+        Some(_) => status == StatusCode::SWITCHING_PROTOCOLS,
+        None => status.is_success(),
+    };
+    if !switching {
+        let mut res = Response::new(body::full(grpc_response.body));
+        *res.status_mut() = status;
+        *res.headers_mut() = headers;
+        return Ok(res);
+    }
+
+    if let Some(protocol) = protocol {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, worker_protocol.unwrap_or(protocol));
+    }
+    let request_guard = connections.start_request();
+    tokio::spawn(async move {
+        let _request_guard = request_guard;
+        let _slot = slot;
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                if let Err(e) = splice(TokioIo::new(upgraded), sender, frames).await {
+                    eprintln!("upgraded connection error: {}", e);
+                }
+            }
+            Err(e) => eprintln!("upgrade error: {}", e),
+        }
+    });
+
+    let mut res = Response::new(body::full(""));
+    *res.status_mut() = status;
+    *res.headers_mut() = headers;
+    Ok(res)
+}
+
+// Copies bytes both ways until the worker ends its stream. The client
+// closing its side only ends the stream towards the worker.
+async fn splice<T>(
+    upgraded: T,
+    sender: mpsc::Sender<UpgradeFrame>,
+    mut frames: tonic::Streaming<UpgradeFrame>,
+) -> io::Result<()>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(upgraded);
+
+    let to_worker = async move {
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            let read = client_read.read(&mut buf).await?;
+            if read == 0 {
+                return Ok::<(), io::Error>(());
+            }
+            let frame = UpgradeFrame {
+                frame: Some(Frame::Data(buf[..read].to_vec())),
+            };
+            if sender.send(frame).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+    let from_worker = async move {
+        while let Some(frame) = frames.message().await.map_err(io::Error::other)? {
+            if let Some(Frame::Data(data)) = frame.frame {
+                client_write.write_all(&data).await?;
+            }
+        }
+        client_write.shutdown().await
+    };
+
+    let mut to_worker = pin!(to_worker);
+    let mut from_worker = pin!(from_worker);
+    let mut sending = true;
+    loop {
+        tokio::select! {
+            result = &mut to_worker, if sending => {
+                sending = false;
+                result?;
+            }
+            result = &mut from_worker => return result,
+        }
+    }
+}
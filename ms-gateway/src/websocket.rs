@@ -0,0 +1,263 @@
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{self, CloseFrame, Role};
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+
+// httpgrpc - protos
+use protos::httpgrpc::web_socket_frame::Frame;
+use protos::httpgrpc::{
+    web_socket_message, HttpRequest, WebSocketClose, WebSocketFrame, WebSocketMessage,
+};
+
+use crate::body::{self, ResponseBody};
+use crate::config::WebSocketConfig;
+use crate::connection::{ConnectionSlot, ConnectionStats};
+use crate::convert::{to_http_headers, to_http_status};
+use crate::pool::WorkerPool;
+use crate::{error_response, proxy_headers};
+
+// Messages buffered towards the worker before reading from the client pauses
+const FRAME_BUFFER: usize = 16;
+// Close code of a close frame without one
+const NO_STATUS_CODE: u32 = 1005;
+
+/// Answers a WebSocket handshake after the worker accepts it over the
+/// `WebSocket` stream, then relays messages between the client and the
+/// worker in a task counted as a request in flight.
+///
+/// Invalid handshakes get 400 or 426 without reaching a worker, and a worker
+/// rejecting the handshake has its response returned as is.
+pub async fn forward(
+    pool: &WorkerPool,
+    grpc_request: HttpRequest,
+    request_headers: &HeaderMap,
+    on_upgrade: OnUpgrade,
+    connections: &Arc<ConnectionStats>,
+    slot: Option<Arc<ConnectionSlot>>,
+    config: &WebSocketConfig,
+) -> Result<Response<ResponseBody>, tonic::Status> {
+    let version = request_headers.get(header::SEC_WEBSOCKET_VERSION);
+    if version.map(HeaderValue::as_bytes) != Some(b"13") {
+        let mut res = error_response(StatusCode::UPGRADE_REQUIRED);
+        res.headers_mut().insert(
+            header::SEC_WEBSOCKET_VERSION,
+            HeaderValue::from_static("13"),
+        );
+        return Ok(res);
+    }
+    let Some(key) = request_headers.get(header::SEC_WEBSOCKET_KEY) else {
+        return Ok(error_response(StatusCode::BAD_REQUEST));
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    let (sender, receiver) = mpsc::channel(FRAME_BUFFER);
+    let first = WebSocketFrame {
+        frame: Some(Frame::Request(grpc_request)),
+    };
+    // The receiver is alive and the buffer empty
+    let _ = sender.try_send(first);
+
+    let mut frames = pool
+        .web_socket(ReceiverStream::new(receiver))
+        .await?
+        .into_inner();
+    let grpc_response = match frames.message().await? {
+        Some(WebSocketFrame {
+            frame: Some(Frame::Response(grpc_response)),
+        }) => grpc_response,
+        _ => {
+            return Err(tonic::Status::internal(
+                "the worker did not start the WebSocket stream with a response",
+            ))
+        }
+    };
+
+    let status = to_http_status(grpc_response.status);
+    let mut headers = to_http_headers(grpc_response.headers);
+    proxy_headers::strip_hop_by_hop(&mut headers);
+
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        let mut res = Response::new(body::full(grpc_response.body));
+        *res.status_mut() = status;
+        *res.headers_mut() = headers;
+        return Ok(res);
+    }
+
+    // The worker may pick a subprotocol, the handshake itself is ours
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(
+        header::SEC_WEBSOCKET_ACCEPT,
+        HeaderValue::from_str(&accept).expect("base64 is a valid header value"),
+    );
+
+    let request_guard = connections.start_request();
+    let config = config.clone();
+    tokio::spawn(async move {
+        let _request_guard = request_guard;
+        let _slot = slot;
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let ws_config = protocol::WebSocketConfig {
+                    max_message_size: Some(config.max_message_size),
+                    max_frame_size: Some(config.max_message_size),
+                    ..Default::default()
+                };
+                let client = WebSocketStream::from_raw_socket(
+                    TokioIo::new(upgraded),
+                    Role::Server,
+                    Some(ws_config),
+                )
+                .await;
+                relay(client, sender, frames, &config).await;
+            }
+            Err(e) => eprintln!("upgrade error: {}", e),
+        }
+    });
+
+    let mut res = Response::new(body::full(""));
+    *res.status_mut() = status;
+    *res.headers_mut() = headers;
+    Ok(res)
+}
+
+// Relays messages both ways until either side goes away. Pings from the
+// client are answered here and also passed to the worker.
+async fn relay<T>(
+    client: WebSocketStream<T>,
+    sender: mpsc::Sender<WebSocketFrame>,
+    mut frames: tonic::Streaming<WebSocketFrame>,
+    config: &WebSocketConfig,
+) where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut client_sink, mut client_stream) = client.split();
+    let idle_timeout = Duration::from_millis(config.idle_timeout_ms);
+    let mut idle = pin!(tokio::time::sleep(idle_timeout));
+
+    let close_code = loop {
+        tokio::select! {
+            message = client_stream.next() => match message {
+                Some(Ok(message)) => {
+                    idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+                    let Some(message) = to_grpc(message) else {
+                        continue;
+                    };
+                    let frame = WebSocketFrame {
+                        frame: Some(Frame::Message(message)),
+                    };
+                    if sender.send(frame).await.is_err() {
+                        break None;
+                    }
+                }
+                Some(Err(Error::Capacity(e))) => {
+                    eprintln!("WebSocket message refused: {}", e);
+                    break Some((CloseCode::Size, "message too big"));
+                }
+                Some(Err(e)) => {
+                    eprintln!("WebSocket connection error: {}", e);
+                    break None;
+                }
+                None => break None,
+            },
+            frame = frames.message() => match frame {
+                Ok(Some(WebSocketFrame {
+                    frame: Some(Frame::Message(message)),
+                })) => {
+                    idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+                    let Some(message) = from_grpc(message) else {
+                        continue;
+                    };
+                    if client_sink.send(message).await.is_err() {
+                        break None;
+                    }
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    // Sends a close frame unless the worker relayed one
+                    let _ = client_sink.close().await;
+                    break None;
+                }
+                Err(status) => {
+                    eprintln!("grpc error: {}", status);
+                    break Some((CloseCode::Error, "worker error"));
+                }
+            },
+            _ = &mut idle, if !idle_timeout.is_zero() => {
+                break Some((CloseCode::Away, "idle timeout"));
+            }
+        }
+    };
+
+    // Both sides are told why the executor ended the connection
+    if let Some((code, reason)) = close_code {
+        let close = WebSocketClose {
+            code: u16::from(code).into(),
+            reason: reason.to_string(),
+        };
+        let frame = WebSocketFrame {
+            frame: Some(Frame::Message(WebSocketMessage {
+                message: Some(web_socket_message::Message::Close(close)),
+            })),
+        };
+        let _ = sender.send(frame).await;
+        let _ = client_sink
+            .send(Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            })))
+            .await;
+    }
+}
+
+fn to_grpc(message: Message) -> Option<WebSocketMessage> {
+    let message = match message {
+        Message::Text(text) => web_socket_message::Message::Text(text),
+        Message::Binary(data) => web_socket_message::Message::Binary(data),
+        Message::Ping(data) => web_socket_message::Message::Ping(data),
+        Message::Pong(data) => web_socket_message::Message::Pong(data),
+        Message::Close(frame) => web_socket_message::Message::Close(match frame {
+            Some(frame) => WebSocketClose {
+                code: u16::from(frame.code).into(),
+                reason: frame.reason.into_owned(),
+            },
+            None => WebSocketClose {
+                code: NO_STATUS_CODE,
+                reason: String::new(),
+            },
+        }),
+        Message::Frame(_) => return None,
+    };
+    Some(WebSocketMessage {
+        message: Some(message),
+    })
+}
+
+fn from_grpc(message: WebSocketMessage) -> Option<Message> {
+    let message = match message.message? {
+        web_socket_message::Message::Text(text) => Message::Text(text),
+        web_socket_message::Message::Binary(data) => Message::Binary(data),
+        web_socket_message::Message::Ping(data) => Message::Ping(data),
+        web_socket_message::Message::Pong(data) => Message::Pong(data),
+        web_socket_message::Message::Close(close) => match u16::try_from(close.code) {
+            Ok(code) if close.code != NO_STATUS_CODE => Message::Close(Some(CloseFrame {
+                code: CloseCode::from(code),
+                reason: close.reason.into(),
+            })),
+            _ => Message::Close(None),
+        },
+    };
+    Some(message)
+}
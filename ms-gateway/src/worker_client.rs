@@ -0,0 +1,94 @@
+use futures::Stream;
+use prost::Message;
+use tonic::transport::Channel;
+
+// httpgrpc - protos
+use protos::httpgrpc::http_client::HttpClient;
+use protos::httpgrpc::{HttpRequest, HttpResponse, UpgradeFrame, WebSocketFrame};
+
+use crate::config::GrpcConfig;
+
+/// gRPC client to the workers.
+///
+/// Holds a compressed and an uncompressed client over the same channel and
+/// picks one per request, so small messages skip the compression cost.
+#[derive(Debug, Clone)]
+pub struct WorkerClient {
+    plain: HttpClient<Channel>,
+    compressed: Option<HttpClient<Channel>>,
+    compression_threshold: usize,
+}
+
+impl WorkerClient {
+    pub fn new(channel: Channel, config: &GrpcConfig) -> WorkerClient {
+        let mut plain = HttpClient::new(channel);
+        for encoding in &config.accept_compression {
+            plain = plain.accept_compressed((*encoding).into());
+        }
+        let compressed = config
+            .send_compression
+            .map(|encoding| plain.clone().send_compressed(encoding.into()));
+
+        WorkerClient {
+            plain,
+            compressed,
+            compression_threshold: config.compression_threshold,
+        }
+    }
+
+    pub async fn handle(
+        &self,
+        request: HttpRequest,
+    ) -> Result<tonic::Response<HttpResponse>, tonic::Status> {
+        let mut client = match &self.compressed {
+            Some(compressed) if request.encoded_len() >= self.compression_threshold => {
+                compressed.clone()
+            }
+            _ => self.plain.clone(),
+        };
+        client.handle(tonic::Request::new(request)).await
+    }
+
+    /// Sends a request whose response comes in parts.
+    pub async fn handle_stream(
+        &self,
+        request: HttpRequest,
+    ) -> Result<tonic::Response<tonic::Streaming<HttpResponse>>, tonic::Status> {
+        let mut client = match &self.compressed {
+            Some(compressed) if request.encoded_len() >= self.compression_threshold => {
+                compressed.clone()
+            }
+            _ => self.plain.clone(),
+        };
+        client.handle_stream(tonic::Request::new(request)).await
+    }
+
+    /// Opens an upgrade stream sending `frames`. Frames carry raw bytes of
+    /// the upgraded connection, so they are not compressed.
+    pub async fn upgrade<S>(
+        &self,
+        frames: S,
+    ) -> Result<tonic::Response<tonic::Streaming<UpgradeFrame>>, tonic::Status>
+    where
+        S: Stream<Item = UpgradeFrame> + Send + 'static,
+    {
+        self.plain
+            .clone()
+            .upgrade(tonic::Request::new(frames))
+            .await
+    }
+
+    /// Opens a WebSocket stream sending `frames`.
+    pub async fn web_socket<S>(
+        &self,
+        frames: S,
+    ) -> Result<tonic::Response<tonic::Streaming<WebSocketFrame>>, tonic::Status>
+    where
+        S: Stream<Item = WebSocketFrame> + Send + 'static,
+    {
+        self.plain
+            .clone()
+            .web_socket(tonic::Request::new(frames))
+            .await
+    }
+}
@@ -0,0 +1,90 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+pub use protos::config::GrpcEncoding;
+
+// Environment variable used to locate the configuration file when no path
+// is given on the command line.
+const CONFIG_ENV: &str = "MS_WORKER_CONFIG";
+
+/// Worker configuration, loaded from a TOML file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the gRPC server binds to, unless `listen_path` is set.
+    pub listen_addr: SocketAddr,
+    /// Unix domain socket to serve on instead of `listen_addr`, for
+    /// executors on the same host.
+    pub listen_path: Option<PathBuf>,
+    pub grpc: GrpcConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: "[::1]:50051".parse().unwrap(),
+            listen_path: None,
+            grpc: GrpcConfig::default(),
+            shutdown: ShutdownConfig::default(),
+        }
+    }
+}
+
+/// Behavior on SIGTERM or Ctrl-C.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Time the worker keeps serving after reporting not-serving to health
+    /// checks, so executors stop routing to it before it exits.
+    pub drain_window_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_window_ms: 5000,
+        }
+    }
+}
+
+/// Message compression on the gRPC connection with the executor.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Encoding of responses, used when the executor accepts it.
+    pub send_compression: Option<GrpcEncoding>,
+    /// Encodings the executor may use for its requests.
+    pub accept_compression: Vec<GrpcEncoding>,
+    /// Responses smaller than this many bytes are sent uncompressed.
+    pub compression_threshold: usize,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            send_compression: None,
+            accept_compression: vec![GrpcEncoding::Gzip, GrpcEncoding::Zstd],
+            compression_threshold: 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the path given as first argument or in
+    /// `MS_WORKER_CONFIG`, falling back to the defaults when neither is set.
+    pub fn load() -> Result<Config, String> {
+        let path = std::env::args()
+            .nth(1)
+            .or_else(|| std::env::var(CONFIG_ENV).ok());
+        let Some(path) = path else {
+            return Ok(Config::default());
+        };
+
+        let contents =
+            std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("invalid {}: {}", path, e))
+    }
+}
@@ -1,26 +1,56 @@
+mod config;
+
 use mimalloc::MiMalloc;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-use std::net::SocketAddr;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use tonic::{transport::Server, Request, Response, Status};
+use prost::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+use tonic_health::server::HealthReporter;
 
-use protos::httpgrpc::{Header, HttpRequest, HttpResponse};
+use protos::httpgrpc::{Header, HttpRequest, HttpResponse, UpgradeFrame, WebSocketFrame};
+use protos::httpgrpc::upgrade_frame::Frame;
+use protos::httpgrpc::web_socket_frame::Frame as WebSocketFrameKind;
+use protos::httpgrpc::web_socket_message::Message as WebSocketMessageKind;
 use protos::httpgrpc::http_server::{Http, HttpServer};
 
+use config::Config;
+
 type HttpResult<T> = Result<Response<T>, Status>;
 
 #[derive(Debug)]
 pub struct GrpcServer {
-    addr: SocketAddr,
+    addr: String,
+    compression_threshold: usize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+// Decrements the in-flight count even when the call is cancelled.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 #[tonic::async_trait]
 impl Http for GrpcServer {
-    async fn handle(&self, request: Request<HttpRequest>) -> HttpResult<HttpResponse> {
-        
+    async fn handle(&self, _request: Request<HttpRequest>) -> HttpResult<HttpResponse> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _guard = InFlightGuard(self.in_flight.clone());
+
         // println!("request [{}] from [{}]", request.into_inner().id, self.addr);
 
         let vec_headers = Header {
@@ -32,33 +62,326 @@ impl Http for GrpcServer {
             values: vec!["1234".to_owned()],
         };
 
-        Ok(Response::new(HttpResponse { 
+        let http_response = HttpResponse { 
             version: "1.1".to_string(), 
             status: 200, 
             headers: vec![vec_headers, vec_headers_2], 
-            body: "Pong".as_bytes().to_vec() }))
+            body: "Pong".as_bytes().to_vec(),
+            trailers: vec![] };
+
+        Ok(self.response(http_response))
+    }
+
+    type HandleStreamStream = ReceiverStream<Result<HttpResponse, Status>>;
+
+    // Sends a Server-Sent Event every second, five in all
+    async fn handle_stream(
+        &self,
+        _request: Request<HttpRequest>,
+    ) -> HttpResult<Self::HandleStreamStream> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlightGuard(self.in_flight.clone());
+
+        let http_response = HttpResponse {
+            version: "1.1".to_string(),
+            status: 200,
+            headers: vec![Header {
+                key: "content-type".to_owned(),
+                values: vec!["text/event-stream".to_owned()],
+            }],
+            body: vec![],
+            trailers: vec![],
+        };
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(16);
+        let _ = sender.try_send(Ok(http_response));
+        tokio::spawn(async move {
+            let _guard = guard;
+            for tick in 1..=5 {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let event = HttpResponse {
+                    body: format!("data: tick {}\n\n", tick).into_bytes(),
+                    ..Default::default()
+                };
+                if sender.send(Ok(event)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    type UpgradeStream = ReceiverStream<Result<UpgradeFrame, Status>>;
+
+    // Switches to the requested protocol and echoes the bytes back, or
+    // connects to the destination of a CONNECT request
+    async fn upgrade(
+        &self,
+        request: Request<Streaming<UpgradeFrame>>,
+    ) -> HttpResult<Self::UpgradeStream> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlightGuard(self.in_flight.clone());
+
+        let mut frames = request.into_inner();
+        let http_request = match frames.message().await? {
+            Some(UpgradeFrame {
+                frame: Some(Frame::Request(http_request)),
+            }) => http_request,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "the first frame must carry the request",
+                ))
+            }
+        };
+        if http_request.method == "CONNECT" {
+            return Ok(Response::new(
+                connect(http_request.uri, frames, guard).await,
+            ));
+        }
+        let protocol = http_request
+            .headers
+            .into_iter()
+            .find(|header| header.key.eq_ignore_ascii_case("upgrade"))
+            .and_then(|header| header.values.into_iter().next())
+            .unwrap_or_default();
+
+        let http_response = HttpResponse {
+            version: "1.1".to_string(),
+            status: 101,
+            headers: vec![Header {
+                key: "upgrade".to_owned(),
+                values: vec![protocol],
+            }],
+            body: vec![],
+            trailers: vec![],
+        };
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(16);
+        let _ = sender.try_send(Ok(UpgradeFrame {
+            frame: Some(Frame::Response(http_response)),
+        }));
+        tokio::spawn(async move {
+            let _guard = guard;
+            while let Ok(Some(frame)) = frames.message().await {
+                if sender.send(Ok(frame)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    type WebSocketStream = ReceiverStream<Result<WebSocketFrame, Status>>;
+
+    // Accepts the handshake and echoes text and binary messages back
+    async fn web_socket(
+        &self,
+        request: Request<Streaming<WebSocketFrame>>,
+    ) -> HttpResult<Self::WebSocketStream> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlightGuard(self.in_flight.clone());
+
+        let mut frames = request.into_inner();
+        match frames.message().await? {
+            Some(WebSocketFrame {
+                frame: Some(WebSocketFrameKind::Request(_)),
+            }) => {}
+            _ => {
+                return Err(Status::invalid_argument(
+                    "the first frame must carry the request",
+                ))
+            }
+        }
+
+        let http_response = HttpResponse {
+            version: "1.1".to_string(),
+            status: 101,
+            headers: vec![],
+            body: vec![],
+            trailers: vec![],
+        };
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(16);
+        let _ = sender.try_send(Ok(WebSocketFrame {
+            frame: Some(WebSocketFrameKind::Response(http_response)),
+        }));
+        tokio::spawn(async move {
+            let _guard = guard;
+            while let Ok(Some(frame)) = frames.message().await {
+                let Some(WebSocketFrameKind::Message(message)) = &frame.frame else {
+                    continue;
+                };
+                let closing = matches!(message.message, Some(WebSocketMessageKind::Close(_)));
+                let echo = matches!(
+                    message.message,
+                    Some(WebSocketMessageKind::Text(_) | WebSocketMessageKind::Binary(_))
+                );
+                // Close messages are answered with the same code
+                if (echo || closing) && sender.send(Ok(frame)).await.is_err() {
+                    break;
+                }
+                if closing {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
     }
 }
 
-async fn shutdown_signal() {
-    // Wait for the CTRL+C signal
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to install CTRL+C signal handler");
+// Opens a TCP connection to `destination` and relays the data frames of
+// the stream over it, answering 200 once connected or 502.
+async fn connect(
+    destination: String,
+    mut frames: Streaming<UpgradeFrame>,
+    guard: InFlightGuard,
+) -> ReceiverStream<Result<UpgradeFrame, Status>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+    let upstream = tokio::net::TcpStream::connect(destination.as_str()).await;
+    let status = if upstream.is_ok() { 200 } else { 502 };
+    let http_response = HttpResponse {
+        version: "1.1".to_string(),
+        status,
+        ..Default::default()
+    };
+    let _ = sender.try_send(Ok(UpgradeFrame {
+        frame: Some(Frame::Response(http_response)),
+    }));
+    let Ok(upstream) = upstream else {
+        return ReceiverStream::new(receiver);
+    };
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        let (mut upstream_read, mut upstream_write) = upstream.into_split();
+        let to_upstream = async move {
+            while let Ok(Some(frame)) = frames.message().await {
+                if let Some(Frame::Data(data)) = frame.frame {
+                    if upstream_write.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            let _ = upstream_write.shutdown().await;
+        };
+        let from_upstream = async move {
+            let mut buf = vec![0u8; 16 * 1024];
+            while let Ok(read) = upstream_read.read(&mut buf).await {
+                if read == 0 {
+                    break;
+                }
+                let frame = UpgradeFrame {
+                    frame: Some(Frame::Data(buf[..read].to_vec())),
+                };
+                if sender.send(Ok(frame)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::join!(to_upstream, from_upstream);
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+impl GrpcServer {
+    // Small responses are not worth compressing
+    fn response(&self, http_response: HttpResponse) -> Response<HttpResponse> {
+        let small = http_response.encoded_len() < self.compression_threshold;
+        let mut response = Response::new(http_response);
+        if small {
+            response.disable_compression();
+        }
+        response
+    }
+}
+
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "Ctrl-C",
+    }
+}
+
+// Reports not-serving on a shutdown signal, then keeps serving for the drain
+// window so executors notice before the worker goes away.
+async fn drain(mut health_reporter: HealthReporter, in_flight: Arc<AtomicUsize>, window: Duration) {
+    let reason = shutdown_signal().await;
+    health_reporter
+        .set_not_serving::<HttpServer<GrpcServer>>()
+        .await;
+    health_reporter
+        .set_service_status("", tonic_health::ServingStatus::NotServing)
+        .await;
+    println!("{} received, not serving, exiting in {:?}", reason, window);
+
+    tokio::time::sleep(window).await;
+    println!(
+        "exiting with {} requests still running",
+        in_flight.load(Ordering::Relaxed)
+    );
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addrs = "[::1]:50051";
-    
-    let addr = addrs.parse().unwrap();
-    let server = GrpcServer { addr };
-        
-    Server::builder()
-    .add_service(HttpServer::new(server))
-    .serve_with_shutdown(addr, shutdown_signal())
-    .await
-    .unwrap();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("config error: {}", e);
+        std::process::exit(1);
+    });
     
+    let addr = config.listen_addr;
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let server = GrpcServer {
+        addr: match &config.listen_path {
+            Some(path) => format!("unix:{}", path.display()),
+            None => addr.to_string(),
+        },
+        compression_threshold: config.grpc.compression_threshold,
+        in_flight: in_flight.clone(),
+    };
+    println!("Listening on {}", server.addr);
+
+    let mut http_server = HttpServer::new(server);
+    for encoding in &config.grpc.accept_compression {
+        http_server = http_server.accept_compressed((*encoding).into());
+    }
+    if let Some(encoding) = config.grpc.send_compression {
+        http_server = http_server.send_compressed(encoding.into());
+    }
+
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<HttpServer<GrpcServer>>()
+        .await;
+
+    let router = Server::builder()
+        .add_service(health_service)
+        .add_service(http_server);
+
+    let server: Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>>>> =
+        match &config.listen_path {
+            Some(path) => {
+                // A socket file left by a previous run would make bind fail
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                Box::pin(router.serve_with_incoming(UnixListenerStream::new(listener)))
+            }
+            None => Box::pin(router.serve(addr)),
+        };
+
+    let window = Duration::from_millis(config.shutdown.drain_window_ms);
+    tokio::select! {
+        result = server => result?,
+        _ = drain(health_reporter, in_flight, window) => {}
+    }
+
+    if let Some(path) = &config.listen_path {
+        let _ = std::fs::remove_file(path);
+    }
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,140 @@
+use std::process::exit;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+
+const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1:3001";
+
+const USAGE: &str = "usage: msctl [--admin ADDR] COMMAND
+
+commands:
+  status                  executor overview
+  endpoints               list worker endpoints
+  add URI [WEIGHT]        add a worker endpoint
+  remove ID               remove a worker endpoint
+  drain ID                stop sending new requests to an endpoint
+  weight ID WEIGHT        change the balancing weight of an endpoint
+  maintenance [on|off]    show or toggle maintenance mode
+  connections             show client connection counts
+  shutdown                start a graceful shutdown
+  upgrade                 hand the listeners to a new executor process
+
+The admin address defaults to $MSCTL_ADMIN, then 127.0.0.1:3001.";
+
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut admin_addr =
+        std::env::var("MSCTL_ADMIN").unwrap_or_else(|_| DEFAULT_ADMIN_ADDR.to_string());
+    if args.first().map(String::as_str) == Some("--admin") {
+        if args.len() < 2 {
+            fail(USAGE);
+        }
+        admin_addr = args.remove(1);
+        args.remove(0);
+    }
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (method, path, body) = match args.as_slice() {
+        ["status"] => (Method::GET, "/status".to_string(), None),
+        ["endpoints"] => (Method::GET, "/endpoints".to_string(), None),
+        ["add", uri] => (
+            Method::POST,
+            "/endpoints".to_string(),
+            Some(json!({ "uri": uri })),
+        ),
+        ["add", uri, weight] => (
+            Method::POST,
+            "/endpoints".to_string(),
+            Some(json!({ "uri": uri, "weight": parse_number(weight) })),
+        ),
+        ["remove", id] => (
+            Method::DELETE,
+            format!("/endpoints/{}", parse_number(id)),
+            None,
+        ),
+        ["drain", id] => (
+            Method::POST,
+            format!("/endpoints/{}/drain", parse_number(id)),
+            None,
+        ),
+        ["weight", id, weight] => (
+            Method::PUT,
+            format!("/endpoints/{}/weight", parse_number(id)),
+            Some(json!({ "weight": parse_number(weight) })),
+        ),
+        ["maintenance"] => (Method::GET, "/maintenance".to_string(), None),
+        ["maintenance", "on"] => (
+            Method::PUT,
+            "/maintenance".to_string(),
+            Some(json!({ "enabled": true })),
+        ),
+        ["maintenance", "off"] => (
+            Method::PUT,
+            "/maintenance".to_string(),
+            Some(json!({ "enabled": false })),
+        ),
+        ["connections"] => (Method::GET, "/connections".to_string(), None),
+        ["shutdown"] => (Method::POST, "/shutdown".to_string(), None),
+        ["upgrade"] => (Method::POST, "/upgrade".to_string(), None),
+        _ => fail(USAGE),
+    };
+
+    match send(&admin_addr, method, &path, body).await {
+        Ok((status, value)) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&value).unwrap_or_default()
+            );
+            if !status.is_success() {
+                exit(1);
+            }
+        }
+        Err(e) => fail(&format!("msctl: {}: {}", admin_addr, e)),
+    }
+}
+
+async fn send(
+    admin_addr: &str,
+    method: Method,
+    path: &str,
+    body: Option<Value>,
+) -> Result<(hyper::StatusCode, Value), Box<dyn std::error::Error + Send + Sync>> {
+    let stream = tokio::net::TcpStream::connect(admin_addr).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            eprintln!("msctl: connection error: {}", err);
+        }
+    });
+
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let req = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(hyper::header::HOST, admin_addr)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))?;
+
+    let res = sender.send_request(req).await?;
+    let status = res.status();
+    let bytes = res.into_body().collect().await?.to_bytes();
+    let value = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+    Ok((status, value))
+}
+
+fn parse_number(value: &str) -> u64 {
+    value
+        .parse()
+        .unwrap_or_else(|_| fail(&format!("msctl: not a number: {}", value)))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(2);
+}
@@ -4,6 +4,6 @@ fn main()  {
         .emit_rerun_if_changed(true)
         .build_server(true)
         .out_dir("./src")
-        .compile(&[proto_file], &["."])
+        .compile_protos(&[proto_file], &["."])
         .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
 }
\ No newline at end of file
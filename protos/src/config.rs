@@ -0,0 +1,24 @@
+// Configuration types shared by the executor and the worker.
+
+use tonic::codec::CompressionEncoding;
+
+/// Compression of the gRPC messages between the executor and the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum GrpcEncoding {
+    Gzip,
+    Zstd,
+}
+
+impl From<GrpcEncoding> for CompressionEncoding {
+    fn from(encoding: GrpcEncoding) -> Self {
+        match encoding {
+            GrpcEncoding::Gzip => CompressionEncoding::Gzip,
+            GrpcEncoding::Zstd => CompressionEncoding::Zstd,
+        }
+    }
+}
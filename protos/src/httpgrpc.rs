@@ -1,5 +1,4 @@
 // This file is @generated by prost-build.
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct HttpRequest {
     #[prost(string, tag = "1")]
@@ -14,8 +13,26 @@ pub struct HttpRequest {
     pub headers: ::prost::alloc::vec::Vec<Header>,
     #[prost(bytes = "vec", tag = "6")]
     pub body: ::prost::alloc::vec::Vec<u8>,
+    /// Connection the request arrived on, as seen by the executor.
+    #[prost(string, tag = "7")]
+    pub peer_addr: ::prost::alloc::string::String,
+    #[prost(string, tag = "8")]
+    pub local_addr: ::prost::alloc::string::String,
+    #[prost(string, tag = "9")]
+    pub scheme: ::prost::alloc::string::String,
+    /// Empty when the connection is not TLS.
+    #[prost(string, tag = "10")]
+    pub tls_protocol: ::prost::alloc::string::String,
+    #[prost(string, tag = "11")]
+    pub tls_cipher: ::prost::alloc::string::String,
+    #[prost(string, tag = "12")]
+    pub tls_sni: ::prost::alloc::string::String,
+    /// Unique per executor process, shared by requests on one connection.
+    #[prost(uint64, tag = "13")]
+    pub connection_id: u64,
+    #[prost(message, repeated, tag = "14")]
+    pub trailers: ::prost::alloc::vec::Vec<Header>,
 }
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct HttpResponse {
     #[prost(string, tag = "1")]
@@ -26,8 +43,10 @@ pub struct HttpResponse {
     pub headers: ::prost::alloc::vec::Vec<Header>,
     #[prost(bytes = "vec", tag = "4")]
     pub body: ::prost::alloc::vec::Vec<u8>,
+    /// Sent after the body; requires a chunked response on HTTP/1.
+    #[prost(message, repeated, tag = "5")]
+    pub trailers: ::prost::alloc::vec::Vec<Header>,
 }
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Header {
     #[prost(string, tag = "1")]
@@ -35,9 +54,78 @@ pub struct Header {
     #[prost(string, repeated, tag = "2")]
     pub values: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UpgradeFrame {
+    #[prost(oneof = "upgrade_frame::Frame", tags = "1, 2, 3")]
+    pub frame: ::core::option::Option<upgrade_frame::Frame>,
+}
+/// Nested message and enum types in `UpgradeFrame`.
+pub mod upgrade_frame {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Frame {
+        #[prost(message, tag = "1")]
+        Request(super::HttpRequest),
+        #[prost(message, tag = "2")]
+        Response(super::HttpResponse),
+        #[prost(bytes, tag = "3")]
+        Data(::prost::alloc::vec::Vec<u8>),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebSocketFrame {
+    #[prost(oneof = "web_socket_frame::Frame", tags = "1, 2, 3")]
+    pub frame: ::core::option::Option<web_socket_frame::Frame>,
+}
+/// Nested message and enum types in `WebSocketFrame`.
+pub mod web_socket_frame {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Frame {
+        #[prost(message, tag = "1")]
+        Request(super::HttpRequest),
+        #[prost(message, tag = "2")]
+        Response(super::HttpResponse),
+        #[prost(message, tag = "3")]
+        Message(super::WebSocketMessage),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebSocketMessage {
+    #[prost(oneof = "web_socket_message::Message", tags = "1, 2, 3, 4, 5")]
+    pub message: ::core::option::Option<web_socket_message::Message>,
+}
+/// Nested message and enum types in `WebSocketMessage`.
+pub mod web_socket_message {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Message {
+        #[prost(string, tag = "1")]
+        Text(::prost::alloc::string::String),
+        #[prost(bytes, tag = "2")]
+        Binary(::prost::alloc::vec::Vec<u8>),
+        #[prost(bytes, tag = "3")]
+        Ping(::prost::alloc::vec::Vec<u8>),
+        #[prost(bytes, tag = "4")]
+        Pong(::prost::alloc::vec::Vec<u8>),
+        #[prost(message, tag = "5")]
+        Close(super::WebSocketClose),
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WebSocketClose {
+    /// 1005 (no status) when the close frame had no code.
+    #[prost(uint32, tag = "1")]
+    pub code: u32,
+    #[prost(string, tag = "2")]
+    pub reason: ::prost::alloc::string::String,
+}
 /// Generated client implementations.
 pub mod http_client {
-    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    #![allow(
+        unused_variables,
+        dead_code,
+        missing_docs,
+        clippy::wildcard_imports,
+        clippy::let_unit_value,
+    )]
     use tonic::codegen::*;
     use tonic::codegen::http::Uri;
     #[derive(Debug, Clone)]
@@ -59,8 +147,8 @@ pub mod http_client {
     where
         T: tonic::client::GrpcService<tonic::body::BoxBody>,
         T::Error: Into<StdError>,
-        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
-        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+        T::ResponseBody: Body<Data = Bytes> + std::marker::Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + std::marker::Send,
     {
         pub fn new(inner: T) -> Self {
             let inner = tonic::client::Grpc::new(inner);
@@ -85,7 +173,7 @@ pub mod http_client {
             >,
             <T as tonic::codegen::Service<
                 http::Request<tonic::body::BoxBody>,
-            >>::Error: Into<StdError> + Send + Sync,
+            >>::Error: Into<StdError> + std::marker::Send + std::marker::Sync,
         {
             HttpClient::new(InterceptedService::new(inner, interceptor))
         }
@@ -128,8 +216,7 @@ pub mod http_client {
                 .ready()
                 .await
                 .map_err(|e| {
-                    tonic::Status::new(
-                        tonic::Code::Unknown,
+                    tonic::Status::unknown(
                         format!("Service was not ready: {}", e.into()),
                     )
                 })?;
@@ -139,29 +226,156 @@ pub mod http_client {
             req.extensions_mut().insert(GrpcMethod::new("httpgrpc.HTTP", "Handle"));
             self.inner.unary(req, path, codec).await
         }
+        /// Responses produced over time, such as Server-Sent Events. The first
+        /// message carries the status and headers; the body of every message is
+        /// sent to the client as soon as it arrives.
+        pub async fn handle_stream(
+            &mut self,
+            request: impl tonic::IntoRequest<super::HttpRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::HttpResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/httpgrpc.HTTP/HandleStream",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("httpgrpc.HTTP", "HandleStream"));
+            self.inner.server_streaming(req, path, codec).await
+        }
+        /// Requests with `Connection: Upgrade`. The executor sends the request
+        /// first and the worker answers with the response. After a 101 response
+        /// both sides send the raw bytes of the upgraded connection, and closing a
+        /// stream closes that direction.
+        pub async fn upgrade(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::UpgradeFrame>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::UpgradeFrame>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/httpgrpc.HTTP/Upgrade");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut().insert(GrpcMethod::new("httpgrpc.HTTP", "Upgrade"));
+            self.inner.streaming(req, path, codec).await
+        }
+        /// WebSocket connections, terminated by the executor. The executor sends
+        /// the handshake request first and the worker accepts with a 101 response
+        /// (any other status rejects it). Both sides then exchange messages; the
+        /// stream ends after a close message or when the connection is lost.
+        pub async fn web_socket(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::WebSocketFrame>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::WebSocketFrame>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/httpgrpc.HTTP/WebSocket");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut().insert(GrpcMethod::new("httpgrpc.HTTP", "WebSocket"));
+            self.inner.streaming(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
 pub mod http_server {
-    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    #![allow(
+        unused_variables,
+        dead_code,
+        missing_docs,
+        clippy::wildcard_imports,
+        clippy::let_unit_value,
+    )]
     use tonic::codegen::*;
     /// Generated trait containing gRPC methods that should be implemented for use with HttpServer.
     #[async_trait]
-    pub trait Http: Send + Sync + 'static {
+    pub trait Http: std::marker::Send + std::marker::Sync + 'static {
         async fn handle(
             &self,
             request: tonic::Request<super::HttpRequest>,
         ) -> std::result::Result<tonic::Response<super::HttpResponse>, tonic::Status>;
+        /// Server streaming response type for the HandleStream method.
+        type HandleStreamStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::HttpResponse, tonic::Status>,
+            >
+            + std::marker::Send
+            + 'static;
+        /// Responses produced over time, such as Server-Sent Events. The first
+        /// message carries the status and headers; the body of every message is
+        /// sent to the client as soon as it arrives.
+        async fn handle_stream(
+            &self,
+            request: tonic::Request<super::HttpRequest>,
+        ) -> std::result::Result<
+            tonic::Response<Self::HandleStreamStream>,
+            tonic::Status,
+        >;
+        /// Server streaming response type for the Upgrade method.
+        type UpgradeStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::UpgradeFrame, tonic::Status>,
+            >
+            + std::marker::Send
+            + 'static;
+        /// Requests with `Connection: Upgrade`. The executor sends the request
+        /// first and the worker answers with the response. After a 101 response
+        /// both sides send the raw bytes of the upgraded connection, and closing a
+        /// stream closes that direction.
+        async fn upgrade(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::UpgradeFrame>>,
+        ) -> std::result::Result<tonic::Response<Self::UpgradeStream>, tonic::Status>;
+        /// Server streaming response type for the WebSocket method.
+        type WebSocketStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::WebSocketFrame, tonic::Status>,
+            >
+            + std::marker::Send
+            + 'static;
+        /// WebSocket connections, terminated by the executor. The executor sends
+        /// the handshake request first and the worker accepts with a 101 response
+        /// (any other status rejects it). Both sides then exchange messages; the
+        /// stream ends after a close message or when the connection is lost.
+        async fn web_socket(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::WebSocketFrame>>,
+        ) -> std::result::Result<tonic::Response<Self::WebSocketStream>, tonic::Status>;
     }
     #[derive(Debug)]
-    pub struct HttpServer<T: Http> {
+    pub struct HttpServer<T> {
         inner: Arc<T>,
         accept_compression_encodings: EnabledCompressionEncodings,
         send_compression_encodings: EnabledCompressionEncodings,
         max_decoding_message_size: Option<usize>,
         max_encoding_message_size: Option<usize>,
     }
-    impl<T: Http> HttpServer<T> {
+    impl<T> HttpServer<T> {
         pub fn new(inner: T) -> Self {
             Self::from_arc(Arc::new(inner))
         }
@@ -215,8 +429,8 @@ pub mod http_server {
     impl<T, B> tonic::codegen::Service<http::Request<B>> for HttpServer<T>
     where
         T: Http,
-        B: Body + Send + 'static,
-        B::Error: Into<StdError> + Send + 'static,
+        B: Body + std::marker::Send + 'static,
+        B::Error: Into<StdError> + std::marker::Send + 'static,
     {
         type Response = http::Response<tonic::body::BoxBody>;
         type Error = std::convert::Infallible;
@@ -272,25 +486,165 @@ pub mod http_server {
                     };
                     Box::pin(fut)
                 }
+                "/httpgrpc.HTTP/HandleStream" => {
+                    #[allow(non_camel_case_types)]
+                    struct HandleStreamSvc<T: Http>(pub Arc<T>);
+                    impl<
+                        T: Http,
+                    > tonic::server::ServerStreamingService<super::HttpRequest>
+                    for HandleStreamSvc<T> {
+                        type Response = super::HttpResponse;
+                        type ResponseStream = T::HandleStreamStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::HttpRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Http>::handle_stream(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = HandleStreamSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/httpgrpc.HTTP/Upgrade" => {
+                    #[allow(non_camel_case_types)]
+                    struct UpgradeSvc<T: Http>(pub Arc<T>);
+                    impl<T: Http> tonic::server::StreamingService<super::UpgradeFrame>
+                    for UpgradeSvc<T> {
+                        type Response = super::UpgradeFrame;
+                        type ResponseStream = T::UpgradeStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<
+                                tonic::Streaming<super::UpgradeFrame>,
+                            >,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Http>::upgrade(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = UpgradeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/httpgrpc.HTTP/WebSocket" => {
+                    #[allow(non_camel_case_types)]
+                    struct WebSocketSvc<T: Http>(pub Arc<T>);
+                    impl<T: Http> tonic::server::StreamingService<super::WebSocketFrame>
+                    for WebSocketSvc<T> {
+                        type Response = super::WebSocketFrame;
+                        type ResponseStream = T::WebSocketStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<
+                                tonic::Streaming<super::WebSocketFrame>,
+                            >,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Http>::web_socket(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = WebSocketSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
-                        Ok(
-                            http::Response::builder()
-                                .status(200)
-                                .header("grpc-status", tonic::Code::Unimplemented as i32)
-                                .header(
-                                    http::header::CONTENT_TYPE,
-                                    tonic::metadata::GRPC_CONTENT_TYPE,
-                                )
-                                .body(empty_body())
-                                .unwrap(),
-                        )
+                        let mut response = http::Response::new(empty_body());
+                        let headers = response.headers_mut();
+                        headers
+                            .insert(
+                                tonic::Status::GRPC_STATUS,
+                                (tonic::Code::Unimplemented as i32).into(),
+                            );
+                        headers
+                            .insert(
+                                http::header::CONTENT_TYPE,
+                                tonic::metadata::GRPC_CONTENT_TYPE,
+                            );
+                        Ok(response)
                     })
                 }
             }
         }
     }
-    impl<T: Http> Clone for HttpServer<T> {
+    impl<T> Clone for HttpServer<T> {
         fn clone(&self) -> Self {
             let inner = self.inner.clone();
             Self {
@@ -302,7 +656,9 @@ pub mod http_server {
             }
         }
     }
-    impl<T: Http> tonic::server::NamedService for HttpServer<T> {
-        const NAME: &'static str = "httpgrpc.HTTP";
+    /// Generated gRPC service name
+    pub const SERVICE_NAME: &str = "httpgrpc.HTTP";
+    impl<T> tonic::server::NamedService for HttpServer<T> {
+        const NAME: &'static str = SERVICE_NAME;
     }
 }
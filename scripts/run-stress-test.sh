@@ -1,2 +1,4 @@
 # sudo apt install wrk
-wrk -t100 -c500 -d99h http://127.0.0.1:3000/
\ No newline at end of file
+# A fixed duration, e.g. ./run-stress-test.sh 60s, gives comparable results
+# across the executor runtime modes of ./start-services.sh
+wrk -t100 -c500 -d${1:-99h} http://127.0.0.1:3000/
@@ -2,8 +2,20 @@
 
 cargo clean && cargo build
 
+# Executor runtime mode: single, processes or thread_per_core, and the
+# number of instances for the last two (0 = one per core), e.g.
+# ./start-services.sh thread_per_core 4
+MODE=${1:-single}
+INSTANCES=${2:-0}
+EXECUTOR_CONFIG=$(mktemp --suffix=.toml)
+cat > "$EXECUTOR_CONFIG" <<CONFIG
+[runtime]
+mode = "$MODE"
+instances = $INSTANCES
+CONFIG
+
 ./target/debug/ms-worker &
-./target/debug/ms-executor
+./target/debug/ms-executor "$EXECUTOR_CONFIG"
 
 # sudo apt install smem
 # watch smem -t -k -P "^./target/debug/ms-executor"